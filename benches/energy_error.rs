@@ -0,0 +1,73 @@
+// Energy error of each integrator against the default, on a few systems with G = 1.
+// Run with `cargo bench --bench energy_error`; each row is one run from the same initial state.
+use std::time::Instant;
+
+use multiverse_simulator::{diagnostics::Diagnostics, integrator::Integrator, simulation::{Body, Physics, Pos, Universe}};
+
+struct Case {
+    name: &'static str,
+    timestep: f64,
+    ticks: i32,
+    // Mass, position and velocity of each body
+    bodies: Vec<(f64, Pos, Pos)>,
+}
+
+fn at(x: f64, vy: f64) -> (Pos, Pos) {
+    (Pos { x, y: 0.0, z: 0.0 }, Pos { x: 0.0, y: vy, z: 0.0 })
+}
+
+fn cases() -> Vec<Case> {
+    let circular = |mass: f64, r: f64| {
+        let (position, velocity) = at(r, (1.0 / r).sqrt());
+        (mass, position, velocity)
+    };
+    let (position, velocity) = at(1.0, 0.3);
+    vec![
+        // e = 0.91, so the periapsis passage is where fixed steps go wrong
+        Case { name: "eccentric orbit", timestep: 0.002, ticks: 50_000, bodies: vec![(1.0, Pos::default(), Pos::default()), (1e-6, position, velocity)] },
+        // A star with two giant planets roughly like Jupiter and Saturn, over about 300 Jupiter orbits
+        Case {
+            name: "two planets",
+            timestep: 0.5,
+            ticks: 150_000,
+            bodies: vec![(1.0, Pos::default(), Pos::default()), circular(1e-3, 5.2), circular(3e-4, 9.5)],
+        },
+        // Many small steps of a circular orbit, where rounding rather than truncation error dominates
+        Case { name: "circular, small steps", timestep: 1e-3, ticks: 1_000_000, bodies: vec![(1.0, Pos::default(), Pos::default()), circular(1e-6, 1.0)] },
+    ]
+}
+
+fn run(case: &Case, integrator: Integrator, compensated: bool, tolerance: Option<f64>) {
+    let physics = Physics { gravitational_constant: 1.0, timestep: case.timestep, integrator, compensated, tolerance, ..Default::default() };
+    let mut universe = Universe::with_physics(physics);
+    for (mass, position, velocity) in &case.bodies {
+        universe.add_body(Body { mass: *mass, position: *position, velocity: *velocity, ..Body::new() });
+    }
+    let initial = Diagnostics::new(&universe).total_energy;
+    let error = |universe: &Universe| ((Diagnostics::new(universe).total_energy - initial) / initial).abs();
+    let start = Instant::now();
+    let mut worst: f64 = 0.0;
+    let every = (case.ticks / 1000).max(1);
+    for _ in 0..case.ticks / every {
+        universe.tick_for(every);
+        worst = worst.max(error(&universe));
+    }
+    let method = match tolerance {
+        Some(tolerance) => format!("adaptive, tolerance {:e}", tolerance),
+        None => format!("{:?}{}", integrator, if compensated { ", compensated" } else { "" }),
+    };
+    println!("  {:<34} {:>10} {:>14.3e} {:>14.3e} {:>10.2?}", method, universe.substeps, worst, error(&universe), start.elapsed());
+}
+
+fn main() {
+    for case in cases() {
+        println!("{}: {} ticks of {}", case.name, case.ticks, case.timestep);
+        println!("  {:<34} {:>10} {:>14} {:>14} {:>10}", "integrator", "substeps", "max |dE/E|", "final |dE/E|", "time");
+        for integrator in [Integrator::SymplecticEuler, Integrator::Leapfrog, Integrator::Yoshida4, Integrator::Yoshida6] {
+            run(&case, integrator, false, None);
+            run(&case, integrator, true, None);
+        }
+        run(&case, Integrator::default(), false, Some(1e-10));
+        println!();
+    }
+}
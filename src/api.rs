@@ -0,0 +1,664 @@
+use std::{future::{ready, Ready}, sync::{mpsc::{self, Sender}, OnceLock}};
+
+use actix_web::{delete, dev::Payload, error::InternalError, get, http::header, patch, post, web, FromRequest, HttpRequest, HttpResponse, Responder, Scope};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{delta::Delta, diagnostics::Diagnostics, divergence::{self, DivergenceOptions}, ensemble::{Ensemble, EnsembleError}, events::Event, handle::Handle, multiverse::{AdvanceError, PruneError, SquashError}, multiverse_manager::MultiverseCommand, orbit::{self, OrbitalElements}, registry::{CreateMultiverseArgs, Registry, RegistryError, DEFAULT_MULTIVERSE}, scenario::Scenario, sweep::Sweep};
+
+pub static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+#[derive(Deserialize)]
+pub struct NodePath{
+    pub uuid: Uuid
+}
+
+#[derive(Deserialize)]
+pub struct EnsemblePath{
+    pub ensemble: Uuid
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MultiverseInfo{
+    pub id: String,
+    // The original root first
+    pub roots: Vec<Handle>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BranchArgs{
+    pub deltas: Vec<Delta>,
+    pub duration: i32
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SweepArgs{
+    #[serde(flatten)]
+    pub sweep: Sweep,
+    pub duration: i32
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EnsembleArgs{
+    #[serde(flatten)]
+    pub ensemble: Ensemble,
+    pub duration: i32
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EnsembleStatisticsArgs{
+    // Ticks since the members branched, defaulting to each member's own length
+    pub age: Option<i32>
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RebaseArgs{
+    pub onto: Uuid,
+    // Defaults to the source node's own relative_age
+    pub duration: Option<i32>
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SquashArgs{
+    // Last node of the chain, a descendant of the node being squashed into
+    pub to: Uuid
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct AdvanceArgs{
+    pub duration: i32
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TrackArgs{
+    // Also sample every this many ticks within each node
+    pub every: Option<i32>
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct OrbitArgs{
+    // Defaults to the secondary's dominant attractor
+    pub primary: Option<Uuid>,
+    // Defaults to every body that has a primary
+    pub secondary: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EventArgs{
+    // Only events of this kind, such as "close_approach"
+    pub kind: Option<String>,
+    // Only events involving this body
+    pub body: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct DiffArgs{
+    pub a: Uuid,
+    pub b: Uuid,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct DivergenceArgs{
+    pub a: Uuid,
+    pub b: Uuid,
+    // Ticks to replay past the common ancestor, defaulting to the longer branch
+    pub duration: Option<i32>,
+    // Sample every this many ticks, defaulting to every tick
+    pub every: Option<i32>,
+    // Fraction of the system's size at which the branches count as decorrelated
+    pub threshold: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct EditArgs{
+    pub deltas: Vec<Delta>,
+}
+
+// Sends a command to a multiverse thread and waits for its reply on the blocking thread pool,
+// so a long replay doesn't hold up a worker. None means the multiverse thread is no longer listening.
+pub async fn request<T: Send + 'static>(chan: &Sender<MultiverseCommand>, command: impl FnOnce(Sender<T>) -> MultiverseCommand) -> Option<T> {
+    let (tx, rx) = mpsc::channel();
+    chan.send(command(tx)).ok()?;
+    web::block(move || rx.recv()).await.ok()?.ok()
+}
+
+// The multiverse that the unscoped routes work on
+pub fn default_multiverse() -> Option<Sender<MultiverseCommand>> {
+    REGISTRY.get()?.get(DEFAULT_MULTIVERSE)
+}
+
+// The multiverse a request is aimed at: the one named by the {multiverse} path segment, or the default one
+pub struct Target{
+    chan: Sender<MultiverseCommand>,
+    // Where this multiverse's routes are mounted
+    prefix: String,
+}
+
+impl Target {
+    pub async fn request<T: Send + 'static>(&self, command: impl FnOnce(Sender<T>) -> MultiverseCommand) -> Option<T> {
+        request(&self.chan, command).await
+    }
+
+    fn created(&self, handle: Handle) -> HttpResponse {
+        HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("{}/nodes/{}", self.prefix, handle.id)))
+            .json(handle)
+    }
+}
+
+impl FromRequest for Target {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Target, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let (id, prefix) = match req.match_info().get("multiverse") {
+            Some(id) => (id, format!("/api/v1/multiverses/{}", id)),
+            None => (DEFAULT_MULTIVERSE, String::from("/api/v1")),
+        };
+        let target = match REGISTRY.get().map(|registry| registry.get(id)) {
+            Some(Some(chan)) => Ok(Target { chan, prefix }),
+            Some(None) => Err(InternalError::from_response("Multiverse not found", multiverse_not_found()).into()),
+            None => Err(InternalError::from_response("Multiverse is not running", unavailable()).into()),
+        };
+        ready(target)
+    }
+}
+
+pub(crate) fn unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body("Multiverse is not running")
+}
+
+pub(crate) fn node_not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Node not found. Please double check the submitted UUID")
+}
+
+fn multiverse_not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Multiverse not found. Please double check the submitted id")
+}
+
+fn registry_error(e: RegistryError) -> HttpResponse {
+    match e {
+        RegistryError::NotFound => multiverse_not_found(),
+        RegistryError::AlreadyExists => HttpResponse::Conflict().body(e.to_string()),
+        RegistryError::InvalidId | RegistryError::InvalidScenario(_) | RegistryError::DefaultIsPermanent => HttpResponse::BadRequest().body(e.to_string()),
+        RegistryError::Storage(_) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/nodes")]
+async fn list_nodes(target: Target) -> impl Responder {
+    match target.request(MultiverseCommand::GetNodes).await {
+        Some(nodes) => HttpResponse::Ok().json(nodes),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}")]
+async fn get_node(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::GetNode((handle, tx))).await {
+        Some(Some(node)) => HttpResponse::Ok().json(node),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+// Removes the node and everything descending from it, replying with the handles that were removed
+#[delete("/nodes/{uuid}")]
+async fn delete_node(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::Prune((handle, tx))).await {
+        Some(Ok(removed)) => HttpResponse::Ok().json(removed),
+        Some(Err(PruneError::NotFound)) => node_not_found(),
+        Some(Err(e)) => HttpResponse::Conflict().body(e.to_string()),
+        None => unavailable(),
+    }
+}
+
+#[patch("/nodes/{uuid}")]
+async fn edit_node(target: Target, path: web::Path<NodePath>, json: web::Json<EditArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = json.into_inner();
+    match target.request(|tx| MultiverseCommand::EditNode((handle, args.deltas, tx))).await {
+        Some(true) => HttpResponse::NoContent().finish(),
+        Some(false) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/advance")]
+async fn advance_node(target: Target, path: web::Path<NodePath>, json: web::Json<AdvanceArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let duration = json.into_inner().duration;
+    match target.request(|tx| MultiverseCommand::AdvanceNode((handle, duration, tx))).await {
+        Some(Ok(new_handle)) => target.created(new_handle),
+        Some(Err(AdvanceError::NotFound)) => node_not_found(),
+        Some(Err(e)) => HttpResponse::Conflict().body(e.to_string()),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/branches")]
+async fn branch_node(target: Target, path: web::Path<NodePath>, json: web::Json<BranchArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = json.into_inner();
+    match target.request(|tx| MultiverseCommand::Branch((handle, args.deltas, args.duration, tx))).await {
+        Some(Some(new_handle)) => target.created(new_handle),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/sweeps")]
+async fn sweep_node(target: Target, path: web::Path<NodePath>, json: web::Json<SweepArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = json.into_inner();
+    let variants = match args.sweep.variants() {
+        Ok(variants) => variants,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match target.request(|tx| MultiverseCommand::Sweep((handle, variants, args.duration, tx))).await {
+        Some(Some(new_handles)) => HttpResponse::Created().json(new_handles),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/ensembles")]
+async fn create_ensemble(target: Target, path: web::Path<NodePath>, json: web::Json<EnsembleArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = json.into_inner();
+    if let Err(e) = args.ensemble.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    match target.request(|tx| MultiverseCommand::CreateEnsemble((handle, args.ensemble, args.duration, tx))).await {
+        Some(Ok(created)) => HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("{}/ensembles/{}/statistics", target.prefix, created.ensemble)))
+            .json(created),
+        Some(Err(EnsembleError::NotFound)) => node_not_found(),
+        Some(Err(e)) => HttpResponse::BadRequest().body(e.to_string()),
+        None => unavailable(),
+    }
+}
+
+#[get("/ensembles/{ensemble}/statistics")]
+async fn ensemble_statistics(target: Target, path: web::Path<EnsemblePath>, query: web::Query<EnsembleStatisticsArgs>) -> impl Responder {
+    let id = path.ensemble;
+    let age = query.into_inner().age;
+    if age.is_some_and(|a| a < 0) {
+        return HttpResponse::BadRequest().body("age can't be negative");
+    }
+    match target.request(|tx| MultiverseCommand::EnsembleStatistics((id, age, tx))).await {
+        Some(Some(statistics)) => HttpResponse::Ok().json(statistics),
+        Some(None) => HttpResponse::NotFound().body("Ensemble not found"),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/rebase")]
+async fn rebase_node(target: Target, path: web::Path<NodePath>, json: web::Json<RebaseArgs>) -> impl Responder {
+    let source = Handle::from(path.uuid);
+    let args = json.into_inner();
+    let onto = Handle::from(args.onto);
+    match target.request(|tx| MultiverseCommand::Rebase((source, onto, args.duration, tx))).await {
+        Some(Some(new_handle)) => target.created(new_handle),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[post("/nodes/{uuid}/squash")]
+async fn squash_node(target: Target, path: web::Path<NodePath>, json: web::Json<SquashArgs>) -> impl Responder {
+    let from = Handle::from(path.uuid);
+    let to = Handle::from(json.into_inner().to);
+    match target.request(|tx| MultiverseCommand::Squash((from, to, tx))).await {
+        Some(Ok(node)) => HttpResponse::Ok().json(node),
+        Some(Err(SquashError::NotFound)) => node_not_found(),
+        Some(Err(e)) => HttpResponse::Conflict().body(e.to_string()),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/universe")]
+async fn get_universe(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::GetUniverse((handle, tx))).await {
+        Some(Some(universe)) => HttpResponse::Ok().json(universe),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/universe/hash")]
+async fn get_universe_hash(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::GetUniverseHash((handle, tx))).await {
+        Some(Some(hash)) => HttpResponse::Ok().json(hash),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/timeline")]
+async fn get_timeline(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::GetTimneline((handle, tx))).await {
+        Some(Some(timeline)) => HttpResponse::Ok().json(timeline),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/universe/diagnostics")]
+async fn get_diagnostics(target: Target, path: web::Path<NodePath>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    match target.request(|tx| MultiverseCommand::GetUniverse((handle, tx))).await {
+        Some(Some(universe)) => HttpResponse::Ok().json(Diagnostics::new(&universe)),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/timeline/diagnostics")]
+async fn track_diagnostics(target: Target, path: web::Path<NodePath>, query: web::Query<TrackArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let every = query.into_inner().every;
+    if every.is_some_and(|n| n < 1) {
+        return HttpResponse::BadRequest().body("every must be at least 1");
+    }
+    match target.request(|tx| MultiverseCommand::TrackDiagnostics((handle, every, tx))).await {
+        Some(Some(samples)) => HttpResponse::Ok().json(samples),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes/{uuid}/universe/orbits")]
+async fn get_orbits(target: Target, path: web::Path<NodePath>, query: web::Query<OrbitArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = query.into_inner();
+    let universe = match target.request(|tx| MultiverseCommand::GetUniverse((handle, tx))).await {
+        Some(Some(universe)) => universe,
+        Some(None) => return node_not_found(),
+        None => return unavailable(),
+    };
+    let body_not_found = |id: Uuid| HttpResponse::NotFound().body(format!("Body {} not found in this universe", id));
+    match (args.primary, args.secondary) {
+        (None, None) => HttpResponse::Ok().json(orbit::all_elements(&universe)),
+        (Some(_), None) => HttpResponse::BadRequest().body("primary requires a secondary"),
+        (primary, Some(secondary)) => {
+            let Some(secondary_body) = universe.get_body(secondary) else {
+                return body_not_found(secondary);
+            };
+            let primary = match primary {
+                Some(primary) => primary,
+                None => match orbit::hierarchy(&universe).into_iter().find(|(body, _)| *body == secondary) {
+                    Some((_, Some(primary))) => primary,
+                    _ => return HttpResponse::Ok().json(Vec::<OrbitalElements>::new()),
+                },
+            };
+            let Some(primary_body) = universe.get_body(primary) else {
+                return body_not_found(primary);
+            };
+            if primary == secondary {
+                return HttpResponse::BadRequest().body("primary and secondary must be different bodies");
+            }
+            // Empty for a degenerate orbit, like a body with no primary
+            let elements: Vec<OrbitalElements> = OrbitalElements::new(primary_body, secondary_body, universe.physics.gravitational_constant).into_iter().collect();
+            HttpResponse::Ok().json(elements)
+        },
+    }
+}
+
+#[get("/nodes/{uuid}/universe/events")]
+async fn get_events(target: Target, path: web::Path<NodePath>, query: web::Query<EventArgs>) -> impl Responder {
+    let handle = Handle::from(path.uuid);
+    let args = query.into_inner();
+    match target.request(|tx| MultiverseCommand::GetUniverse((handle, tx))).await {
+        Some(Some(universe)) => {
+            let events: Vec<Event> = universe.events.into_iter()
+                .filter(|e| args.kind.as_deref().is_none_or(|kind| e.kind.name() == kind))
+                .filter(|e| args.body.is_none_or(|body| e.bodies.contains(&body)))
+                .collect();
+            HttpResponse::Ok().json(events)
+        },
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/diff")]
+async fn diff_nodes(target: Target, query: web::Query<DiffArgs>) -> impl Responder {
+    let args = query.into_inner();
+    let mut universes = vec![];
+    for node in [args.a, args.b] {
+        match target.request(|tx| MultiverseCommand::GetUniverse((Handle::from(node), tx))).await {
+            Some(Some(universe)) => universes.push(universe),
+            Some(None) => return HttpResponse::NotFound().body(format!("Node {} not found", node)),
+            None => return unavailable(),
+        }
+    }
+    HttpResponse::Ok().json(universes[0].diff(&universes[1]))
+}
+
+#[get("/divergence")]
+async fn track_divergence(target: Target, query: web::Query<DivergenceArgs>) -> impl Responder {
+    let args = query.into_inner();
+    if args.every.is_some_and(|n| n < 1) {
+        return HttpResponse::BadRequest().body("every must be at least 1");
+    }
+    if args.duration.is_some_and(|n| !(0..=divergence::MAX_DURATION).contains(&n)) {
+        return HttpResponse::BadRequest().body(format!("duration must be between 0 and {}", divergence::MAX_DURATION));
+    }
+    if args.threshold.is_some_and(|t| !(t > 0.0 && t.is_finite())) {
+        return HttpResponse::BadRequest().body("threshold must be positive");
+    }
+    let (a, b) = (Handle::from(args.a), Handle::from(args.b));
+    let options = DivergenceOptions { duration: args.duration, every: args.every, threshold: args.threshold };
+    match target.request(|tx| MultiverseCommand::TrackDivergence((a, b, options, tx))).await {
+        Some(Some(report)) => HttpResponse::Ok().json(report),
+        Some(None) => HttpResponse::NotFound().body("Node not found, or the nodes don't share a root"),
+        None => unavailable(),
+    }
+}
+
+#[get("/roots")]
+async fn list_roots(target: Target) -> impl Responder {
+    match target.request(MultiverseCommand::GetRoots).await {
+        Some(roots) => HttpResponse::Ok().json(roots),
+        None => unavailable(),
+    }
+}
+
+#[post("/roots")]
+async fn create_root(target: Target, json: web::Json<Scenario>) -> impl Responder {
+    let scenario = json.into_inner();
+    if let Err(e) = scenario.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    match target.request(|tx| MultiverseCommand::CreateRoot((scenario, tx))).await {
+        Some(new_handle) => target.created(new_handle),
+        None => unavailable(),
+    }
+}
+
+async fn info(id: String, chan: &Sender<MultiverseCommand>) -> Option<MultiverseInfo> {
+    let roots = request(chan, MultiverseCommand::GetRoots).await?;
+    Some(MultiverseInfo { id, roots })
+}
+
+#[get("/multiverses")]
+async fn list_multiverses() -> impl Responder {
+    let Some(registry) = REGISTRY.get() else {
+        return unavailable();
+    };
+    let mut infos: Vec<MultiverseInfo> = Vec::new();
+    for id in registry.list() {
+        let Some(chan) = registry.get(&id) else {
+            continue;
+        };
+        if let Some(info) = info(id, &chan).await {
+            infos.push(info);
+        }
+    }
+    HttpResponse::Ok().json(infos)
+}
+
+#[post("/multiverses")]
+async fn create_multiverse(json: web::Json<CreateMultiverseArgs>) -> impl Responder {
+    let Some(registry) = REGISTRY.get() else {
+        return unavailable();
+    };
+    // Creating a multiverse opens and seeds its store, so it runs on the blocking thread pool
+    let args = json.into_inner();
+    let id = match web::block(move || registry.create(args)).await {
+        Ok(Ok(id)) => id,
+        Ok(Err(e)) => return registry_error(e),
+        Err(_) => return unavailable(),
+    };
+    let Some(chan) = registry.get(&id) else {
+        return unavailable();
+    };
+    match info(id.clone(), &chan).await {
+        Some(info) => HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("/api/v1/multiverses/{}", id)))
+            .json(info),
+        None => unavailable(),
+    }
+}
+
+#[get("/multiverses/{multiverse}")]
+async fn get_multiverse(path: web::Path<String>) -> impl Responder {
+    let id = path.into_inner();
+    let Some(registry) = REGISTRY.get() else {
+        return unavailable();
+    };
+    let Some(chan) = registry.get(&id) else {
+        return multiverse_not_found();
+    };
+    match info(id, &chan).await {
+        Some(info) => HttpResponse::Ok().json(info),
+        None => unavailable(),
+    }
+}
+
+#[delete("/multiverses/{multiverse}")]
+async fn delete_multiverse(path: web::Path<String>) -> impl Responder {
+    let Some(registry) = REGISTRY.get() else {
+        return unavailable();
+    };
+    // Deleting waits for the multiverse thread to shut down and store its nodes
+    let id = path.into_inner();
+    match web::block(move || registry.delete(&id)).await {
+        Ok(Ok(_)) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => registry_error(e),
+        Err(_) => unavailable(),
+    }
+}
+
+// The routes that work on a single multiverse
+fn multiverse_routes(scope: Scope) -> Scope {
+    scope
+        .service(list_nodes)
+        .service(get_node)
+        .service(edit_node)
+        .service(delete_node)
+        .service(advance_node)
+        .service(branch_node)
+        .service(sweep_node)
+        .service(create_ensemble)
+        .service(rebase_node)
+        .service(squash_node)
+        .service(ensemble_statistics)
+        .service(get_universe)
+        .service(get_universe_hash)
+        .service(get_timeline)
+        .service(get_diagnostics)
+        .service(track_diagnostics)
+        .service(get_orbits)
+        .service(get_events)
+        .service(diff_nodes)
+        .service(track_divergence)
+        .service(list_roots)
+        .service(create_root)
+}
+
+// Unscoped routes work on the default multiverse, so clients from before there were several keep working.
+// The single-multiverse resource is registered ahead of the scope that shares its prefix.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(multiverse_routes(web::scope("/api/v1")
+        .service(list_multiverses)
+        .service(create_multiverse)
+        .service(get_multiverse)
+        .service(delete_multiverse)
+        .service(multiverse_routes(web::scope("/multiverses/{multiverse}")))));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::env;
+
+    use actix_web::{http::{Method, StatusCode}, test, App};
+
+    use super::*;
+    use crate::config::Config;
+
+    // REGISTRY is process-wide, so every route test shares one registry in its own data directory
+    pub(crate) fn registry() -> &'static Registry {
+        REGISTRY.get_or_init(|| {
+            let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+            Registry::open(Config { data_dir, ..Default::default() })
+        })
+    }
+
+    pub(crate) fn root() -> Uuid {
+        let chan = registry().get(DEFAULT_MULTIVERSE).unwrap();
+        let (tx, rx) = mpsc::channel();
+        chan.send(MultiverseCommand::GetRoots(tx)).unwrap();
+        rx.recv().unwrap()[0].id
+    }
+
+    #[actix_web::test]
+    async fn missing_nodes_are_not_found() {
+        registry();
+        let app = test::init_service(App::new().configure(configure)).await;
+        let missing = Uuid::new_v4();
+        for (method, path) in [
+            (Method::GET, format!("/api/v1/nodes/{}", missing)),
+            (Method::GET, format!("/api/v1/nodes/{}/universe", missing)),
+            (Method::GET, format!("/api/v1/nodes/{}/timeline", missing)),
+            (Method::DELETE, format!("/api/v1/nodes/{}", missing)),
+            (Method::GET, format!("/api/v1/multiverses/missing/nodes/{}", missing)),
+        ] {
+            let req = test::TestRequest::default().method(method.clone()).uri(&path).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} {}", method, path);
+        }
+        let req = test::TestRequest::post().uri(&format!("/api/v1/nodes/{}/advance", missing))
+            .set_json(AdvanceArgs { duration: 1 })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn mutating_routes_refuse_get() {
+        registry();
+        let app = test::init_service(App::new().configure(configure)).await;
+        let root = root();
+        let nodes = |body: &[u8]| serde_json::from_slice::<Vec<Handle>>(body).unwrap().len();
+        let before = nodes(&test::call_and_read_body(&app, test::TestRequest::get().uri("/api/v1/nodes").to_request()).await);
+        for path in ["advance", "branches", "sweeps", "ensembles", "rebase", "squash"] {
+            let req = test::TestRequest::get().uri(&format!("/api/v1/nodes/{}/{}", root, path)).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_client_error(), "GET {} answered {}", path, resp.status());
+        }
+        for method in [Method::PUT, Method::POST] {
+            let req = test::TestRequest::default().method(method.clone()).uri(&format!("/api/v1/nodes/{}", root)).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_client_error(), "{} answered {}", method, resp.status());
+        }
+        let after = nodes(&test::call_and_read_body(&app, test::TestRequest::get().uri("/api/v1/nodes").to_request()).await);
+        assert_eq!(before, after);
+    }
+}
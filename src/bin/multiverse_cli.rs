@@ -0,0 +1,325 @@
+// Works directly on the multiverse databases, for scripting experiments without the web server.
+// Don't point this at a data directory that a running server is using.
+use std::{fs, io::{self, Read}, path::PathBuf, process};
+
+use clap::{Parser, Subcommand};
+use multiverse_simulator::{config::{Config, PhysicsArgs}, handle::Handle, delta::Delta, multiverse::{AdvanceError, Multiverse, PruneError, NODE_STORE_FILE}, registry::{self, DEFAULT_MULTIVERSE}, ensemble::{Ensemble, EnsembleError}, scenario, sweep::Sweep};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(version, about = "Inspect and edit a multiverse database without running the server")]
+struct Cli {
+    /// TOML file to read settings from
+    #[arg(long, short, env = "MULTIVERSE_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Directory holding the node and universe databases
+    #[arg(long, env = "MULTIVERSE_DATA_DIR", global = true)]
+    data_dir: Option<PathBuf>,
+    /// Id of the multiverse to work on, as created through the server's /api/v1/multiverses
+    #[arg(long, short, default_value = DEFAULT_MULTIVERSE, global = true)]
+    multiverse: String,
+    // The same physics flags and environment as the server, which a deterministic multiverse needs to line up with it
+    #[command(flatten)]
+    physics: PhysicsArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create the databases and root node if they don't exist yet, printing the root's handle.
+    /// With --scenario, an existing multiverse gets an extra root built from it.
+    Init {
+        /// Scenario to build the root from, e.g. two_body or plummer_sphere
+        #[arg(long)]
+        scenario: Option<String>,
+        /// Scenario parameter as KEY=VALUE, may be repeated
+        #[arg(long = "param", requires = "scenario")]
+        params: Vec<String>,
+    },
+    /// List every node, parents before children
+    List,
+    /// Print a node as JSON
+    Show {
+        node: Uuid,
+        /// Print the node's universe instead, calculating it if needed
+        #[arg(long)]
+        universe: bool,
+    },
+    /// Branch a new child node, printing its handle
+    Branch {
+        node: Uuid,
+        /// JSON file holding a list of deltas, or - for stdin
+        #[arg(long)]
+        deltas: Option<PathBuf>,
+        /// Ticks to advance relative to the parent
+        #[arg(long, default_value_t = 0)]
+        duration: i32,
+    },
+    /// Branch one child node per point of a parameter grid, printing their handles
+    Sweep {
+        node: Uuid,
+        /// JSON file holding the template delta and axes to sweep, or - for stdin
+        #[arg(long)]
+        spec: PathBuf,
+        /// Ticks to advance relative to the parent
+        #[arg(long, default_value_t = 0)]
+        duration: i32,
+    },
+    /// Branch a Monte Carlo ensemble of randomly perturbed children, printing the ensemble's id and seed, then its members
+    Ensemble {
+        node: Uuid,
+        /// JSON file holding the member count, optional seed and perturbations, or - for stdin
+        #[arg(long)]
+        spec: PathBuf,
+        /// Ticks to advance relative to the parent
+        #[arg(long, default_value_t = 0)]
+        duration: i32,
+    },
+    /// Re-apply a node's deltas as a new child of another node, printing its handle
+    Rebase {
+        node: Uuid,
+        /// Node to branch the copy from
+        #[arg(long)]
+        onto: Uuid,
+        /// Ticks to advance relative to the new parent [default: the node's own]
+        #[arg(long)]
+        duration: Option<i32>,
+    },
+    /// Collapse the chain from a node down to one of its descendants into the node, printing the removed handles
+    Squash {
+        node: Uuid,
+        /// Last node of the chain
+        #[arg(long)]
+        to: Uuid,
+    },
+    /// Create the canonical next node, printing its handle
+    Advance {
+        node: Uuid,
+        /// Ticks to advance relative to the parent
+        #[arg(long)]
+        duration: i32,
+    },
+    /// Print the universes from the root down to a node
+    Timeline {
+        node: Uuid,
+    },
+    /// Compare the universes at two nodes, body by body, as b - a
+    Diff {
+        a: Uuid,
+        b: Uuid,
+    },
+    /// Dump every node as JSON
+    Export {
+        /// Include each node's universe, calculating them if needed
+        #[arg(long)]
+        universes: bool,
+        /// Write to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Remove a node and everything descended from it
+    Prune {
+        node: Uuid,
+    },
+}
+
+fn to_json<T: Serialize>(val: &T) -> String {
+    serde_json::to_string_pretty(val).expect("Failed to serialize json")
+}
+
+fn read_deltas(path: Option<PathBuf>) -> Result<Vec<Delta>, String> {
+    match path {
+        None => Ok(vec![]),
+        Some(path) => read_json(path, "deltas"),
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: PathBuf, what: &str) -> Result<T, String> {
+    let text = match path {
+        path if path.as_os_str() == "-" => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).map_err(|e| format!("Failed to read {} from stdin: {}", what, e))?;
+            text
+        },
+        path => fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+    };
+    serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", what, e))
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    let mut config = Config::from_file(cli.config.as_deref())?;
+    if let Some(data_dir) = cli.data_dir {
+        config.data_dir = data_dir;
+    }
+    cli.physics.apply(&mut config.physics);
+    config.validate()?;
+    if !registry::valid_id(&cli.multiverse) {
+        return Err(format!("Invalid multiverse id {}", cli.multiverse));
+    }
+    let data_dir = registry::multiverse_dir(&config.data_dir, &cli.multiverse);
+    // Only init creates a multiverse, so a mistyped directory isn't silently seeded
+    let no_multiverse = || format!("no multiverse at {}, run init", data_dir.display());
+    let initializing = matches!(cli.command, Command::Init { .. });
+    if !initializing && !data_dir.join(NODE_STORE_FILE).exists() {
+        return Err(no_multiverse());
+    }
+    let mut multiverse = Multiverse::open(&data_dir, config.physics);
+    if !initializing && multiverse.root_node.is_none() {
+        return Err(no_multiverse());
+    }
+    let not_found = |node: Uuid| format!("Node {} not found", node);
+
+    match cli.command {
+        Command::Init { scenario, params } => {
+            let root = match (scenario, multiverse.root_node) {
+                (Some(name), _) => {
+                    let scenario = scenario::from_args(&name, &params)?;
+                    scenario.validate().map_err(|e| format!("Invalid scenario: {}", e))?;
+                    multiverse.create_root(&scenario)
+                },
+                (None, Some(root)) => root,
+                (None, None) => multiverse.create_root(&config.scenario),
+            };
+            println!("{}", root.id);
+        },
+        Command::List => {
+            println!("node\tparent\trelative_age\tchildren\tdeltas");
+            for exported in multiverse.export(false).nodes {
+                let node = exported.node;
+                println!("{}\t{}\t{}\t{}\t{}",
+                    exported.handle.id,
+                    node.parent.map(|p| p.id.to_string()).unwrap_or_else(|| String::from("-")),
+                    node.relative_age,
+                    node.descendants().len(),
+                    node.delta.map(|d| d.len()).unwrap_or_default());
+            }
+        },
+        Command::Show { node, universe } => {
+            let handle = Handle::from(node);
+            if universe {
+                let universe = multiverse.get_universe(&handle).ok_or_else(|| not_found(node))?;
+                println!("{}", to_json(&universe));
+            } else {
+                let n = multiverse.get_node(&handle).ok_or_else(|| not_found(node))?;
+                println!("{}", to_json(&n));
+            }
+        },
+        Command::Branch { node, deltas, duration } => {
+            let deltas = read_deltas(deltas)?;
+            let new_handle = multiverse.branch(&Handle::from(node), duration, deltas).ok_or_else(|| not_found(node))?;
+            println!("{}", new_handle.id);
+        },
+        Command::Sweep { node, spec, duration } => {
+            let sweep: Sweep = read_json(spec, "sweep")?;
+            let new_handles = multiverse.sweep(&Handle::from(node), duration, sweep.variants()?).ok_or_else(|| not_found(node))?;
+            for handle in new_handles {
+                println!("{}", handle.id);
+            }
+        },
+        Command::Ensemble { node, spec, duration } => {
+            let ensemble: Ensemble = read_json(spec, "ensemble")?;
+            ensemble.validate()?;
+            let created = multiverse.ensemble(&Handle::from(node), duration, &ensemble).map_err(|e| match e {
+                EnsembleError::NotFound => not_found(node),
+                e => e.to_string(),
+            })?;
+            println!("{}\t{}", created.ensemble, created.seed);
+            for handle in created.members {
+                println!("{}", handle.id);
+            }
+        },
+        Command::Rebase { node, onto, duration } => {
+            multiverse.get_node(&Handle::from(node)).ok_or_else(|| not_found(node))?;
+            let new_handle = multiverse.rebase(&Handle::from(node), &Handle::from(onto), duration).ok_or_else(|| not_found(onto))?;
+            println!("{}", new_handle.id);
+        },
+        Command::Squash { node, to } => {
+            let (_, removed) = multiverse.squash(&Handle::from(node), &Handle::from(to)).map_err(|e| e.to_string())?;
+            for handle in removed {
+                println!("{}", handle.id);
+            }
+        },
+        Command::Advance { node, duration } => {
+            let new_handle = multiverse.advance(&Handle::from(node), duration).map_err(|e| match e {
+                AdvanceError::NotFound => not_found(node),
+                e => e.to_string(),
+            })?;
+            println!("{}", new_handle.id);
+        },
+        Command::Timeline { node } => {
+            let timeline = multiverse.get_timeline(&Handle::from(node)).ok_or_else(|| not_found(node))?;
+            println!("{}", to_json(&timeline));
+        },
+        Command::Diff { a, b } => {
+            let universe_a = multiverse.get_universe(&Handle::from(a)).ok_or_else(|| not_found(a))?;
+            let universe_b = multiverse.get_universe(&Handle::from(b)).ok_or_else(|| not_found(b))?;
+            println!("{}", to_json(&universe_a.diff(&universe_b)));
+        },
+        Command::Export { universes, output } => {
+            let json = to_json(&multiverse.export(universes));
+            match output {
+                Some(path) => fs::write(&path, json).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?,
+                None => println!("{}", json),
+            }
+        },
+        Command::Prune { node } => {
+            let removed = multiverse.prune(&Handle::from(node)).map_err(|e| match e {
+                PruneError::NotFound => not_found(node),
+                e => e.to_string(),
+            })?;
+            for handle in removed {
+                println!("{}", handle.id);
+            }
+        },
+    }
+    multiverse.close()?;
+    Ok(())
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn cli(data_dir: &std::path::Path, args: &[&str]) -> Cli {
+        let mut argv = vec!["multiverse_cli", "--data-dir", data_dir.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn init_branch_list_round_trip() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        run(cli(&data_dir, &["init"])).unwrap();
+        let root = Multiverse::open(&data_dir, Config::default().physics).root_node.unwrap();
+        run(cli(&data_dir, &["branch", &root.id.to_string(), "--duration", "3"])).unwrap();
+        run(cli(&data_dir, &["list"])).unwrap();
+
+        let multiverse = Multiverse::open(&data_dir, Config::default().physics);
+        assert_eq!(multiverse.root_node, Some(root));
+        let root_node = multiverse.get_node(&root).unwrap();
+        assert_eq!(root_node.children.len(), 1);
+        assert_eq!(multiverse.get_node(&root_node.children[0]).unwrap().relative_age, 3);
+        multiverse.close().unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn commands_other_than_init_need_a_multiverse() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let e = run(cli(&data_dir, &["list"])).unwrap_err();
+        assert_eq!(e, format!("no multiverse at {}, run init", data_dir.display()));
+        assert!(!data_dir.exists());
+    }
+}
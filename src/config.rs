@@ -0,0 +1,271 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use clap::{builder::BoolishValueParser, Args, Parser};
+use serde::{Deserialize, Serialize};
+
+use crate::{integrator::Integrator, scenario::{self, Scenario}, simulation::Physics};
+
+// Each setting is resolved in order: command line flag, environment variable, config file, built-in default
+#[derive(Parser, Debug)]
+#[command(version, about = "Serves a multiverse of branching n-body simulations over HTTP")]
+pub struct Cli {
+    /// TOML file to read settings from
+    #[arg(long, short, env = "MULTIVERSE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to bind the web server to
+    #[arg(long, env = "MULTIVERSE_BIND_ADDRESS")]
+    pub bind_address: Option<String>,
+    /// Port to bind the web server to
+    #[arg(long, short, env = "MULTIVERSE_PORT")]
+    pub port: Option<u16>,
+    /// Directory holding the node and universe databases
+    #[arg(long, env = "MULTIVERSE_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Number of HTTP worker threads [default: one per core]
+    #[arg(long, env = "MULTIVERSE_WORKERS")]
+    pub workers: Option<usize>,
+    /// Log filter, e.g. "info" or "actix_web=debug"
+    #[arg(long, env = "MULTIVERSE_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// Also serve the old GET-only routes under /api
+    #[arg(long, env = "MULTIVERSE_LEGACY_ROUTES", value_parser = BoolishValueParser::new())]
+    pub legacy_routes: Option<bool>,
+    #[command(flatten)]
+    pub physics: PhysicsArgs,
+    /// Scenario to build the root from when the data directory is empty
+    #[arg(long, env = "MULTIVERSE_SCENARIO")]
+    pub scenario: Option<String>,
+    /// Scenario parameter as KEY=VALUE, may be repeated
+    #[arg(long = "scenario-param", requires = "scenario")]
+    pub scenario_params: Vec<String>,
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+// The physics flags, shared with multiverse_cli so that both work on a data directory with the same settings.
+// Global so the CLI takes them after its subcommand too.
+#[derive(Args, Debug)]
+pub struct PhysicsArgs {
+    /// Gravitational constant for newly created root universes
+    #[arg(long, global = true, env = "MULTIVERSE_GRAVITATIONAL_CONSTANT")]
+    pub gravitational_constant: Option<f64>,
+    /// Simulated time per tick for newly created root universes
+    #[arg(long, global = true, env = "MULTIVERSE_TIMESTEP")]
+    pub timestep: Option<f64>,
+    /// Derive ids from node handles and check recomputed universes against their first hash
+    #[arg(long, global = true, env = "MULTIVERSE_DETERMINISTIC", value_parser = BoolishValueParser::new())]
+    pub deterministic: Option<bool>,
+    /// Integrate adaptively, keeping the error per substep within this tolerance [default: fixed steps]
+    #[arg(long, global = true, env = "MULTIVERSE_TOLERANCE")]
+    pub tolerance: Option<f64>,
+    /// Integrator for fixed steps [default: symplectic_euler]
+    #[arg(long, global = true, env = "MULTIVERSE_INTEGRATOR", value_enum)]
+    pub integrator: Option<Integrator>,
+    /// Sum forces and fixed steps with Kahan summation, for long runs
+    #[arg(long, global = true, env = "MULTIVERSE_COMPENSATED", value_parser = BoolishValueParser::new())]
+    pub compensated: Option<bool>,
+}
+
+impl PhysicsArgs {
+    pub fn apply(&self, physics: &mut Physics) {
+        if let Some(g) = self.gravitational_constant {
+            physics.gravitational_constant = g;
+        }
+        if let Some(timestep) = self.timestep {
+            physics.timestep = timestep;
+        }
+        if let Some(deterministic) = self.deterministic {
+            physics.deterministic = deterministic;
+        }
+        if self.tolerance.is_some() {
+            physics.tolerance = self.tolerance;
+        }
+        if let Some(integrator) = self.integrator {
+            physics.integrator = integrator;
+        }
+        if let Some(compensated) = self.compensated {
+            physics.compensated = compensated;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    // None leaves the choice to actix, which starts one worker per core
+    pub workers: Option<usize>,
+    pub log_level: String,
+    pub legacy_routes: bool,
+    pub physics: Physics,
+    // Seeds the root of a new multiverse
+    pub scenario: Scenario,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: String::from("0.0.0.0"),
+            port: 8080,
+            data_dir: PathBuf::from("."),
+            workers: None,
+            log_level: String::from("info"),
+            legacy_routes: false,
+            physics: Physics::default(),
+            scenario: Scenario::default(),
+        }
+    }
+}
+
+impl Config {
+    // Reads a config file, falling back to the defaults when there isn't one
+    pub fn from_file(path: Option<&Path>) -> Result<Config, String> {
+        match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))
+            },
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let mut config = Config::from_file(cli.config.as_deref())?;
+        if let Some(bind_address) = &cli.bind_address {
+            config.bind_address = bind_address.clone();
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(data_dir) = &cli.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if cli.workers.is_some() {
+            config.workers = cli.workers;
+        }
+        if let Some(log_level) = &cli.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(legacy_routes) = cli.legacy_routes {
+            config.legacy_routes = legacy_routes;
+        }
+        cli.physics.apply(&mut config.physics);
+        if let Some(name) = &cli.scenario {
+            config.scenario = scenario::from_args(name, &cli.scenario_params)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.workers == Some(0) {
+            return Err(String::from("workers must be at least 1"));
+        }
+        if !(self.physics.timestep.is_finite() && self.physics.timestep > 0.0) {
+            return Err(format!("timestep must be a positive number, got {}", self.physics.timestep));
+        }
+        if !self.physics.gravitational_constant.is_finite() {
+            return Err(format!("gravitational_constant must be finite, got {}", self.physics.gravitational_constant));
+        }
+        if let Some(tolerance) = self.physics.tolerance.filter(|t| !(t.is_finite() && *t > 0.0)) {
+            return Err(format!("tolerance must be a positive number, got {}", tolerance));
+        }
+        self.physics.validate_stepping()?;
+        self.scenario.validate().map_err(|e| format!("Invalid scenario: {}", e))
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Failed to serialize config")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::scenario::TwoBody;
+
+    fn rejected(config: Config, message: &str) {
+        let e = config.validate().unwrap_err();
+        assert!(e.contains(message), "expected {:?} in {:?}", message, e);
+    }
+
+    #[test]
+    fn validate_rejects_bad_settings() {
+        assert!(Config::default().validate().is_ok());
+        rejected(Config { workers: Some(0), ..Default::default() }, "workers");
+        for timestep in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let mut config = Config::default();
+            config.physics.timestep = timestep;
+            rejected(config, "timestep");
+        }
+        let mut config = Config::default();
+        config.physics.gravitational_constant = f64::NAN;
+        rejected(config, "gravitational_constant");
+        for tolerance in [0.0, -1e-9, f64::NAN] {
+            let mut config = Config::default();
+            config.physics.tolerance = Some(tolerance);
+            rejected(config, "tolerance");
+        }
+        let mut config = Config::default();
+        config.physics.tolerance = Some(1e-9);
+        config.physics.compensated = true;
+        rejected(config, "A tolerance can't be combined");
+        let mut config = Config::default();
+        config.physics.tolerance = Some(1e-9);
+        config.physics.integrator = Integrator::Yoshida4;
+        rejected(config, "A tolerance can't be combined");
+        let scenario = Scenario::TwoBody(TwoBody { eccentricity: 1.5, ..Default::default() });
+        rejected(Config { scenario, ..Default::default() }, "Invalid scenario");
+    }
+
+    // The only test that sets MULTIVERSE_* variables, so the others can't see them
+    #[test]
+    fn flags_beat_env_beat_file_beat_defaults() {
+        let path = env::temp_dir().join(format!("multiverse-test-{}.toml", Uuid::new_v4()));
+        fs::write(&path, "port = 1000\nbind_address = \"127.0.0.2\"\nlog_level = \"debug\"\n\n[physics]\ntimestep = 0.5\ngravitational_constant = 2.0\n").unwrap();
+        env::set_var("MULTIVERSE_PORT", "2000");
+        env::set_var("MULTIVERSE_BIND_ADDRESS", "127.0.0.3");
+        env::set_var("MULTIVERSE_TIMESTEP", "0.25");
+        let cli = Cli::try_parse_from(["multiverse", "--config", path.to_str().unwrap(), "--port", "3000", "--timestep", "0.125"]);
+        env::remove_var("MULTIVERSE_PORT");
+        env::remove_var("MULTIVERSE_BIND_ADDRESS");
+        env::remove_var("MULTIVERSE_TIMESTEP");
+        let config = Config::load(&cli.unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let defaults = Config::default();
+        // Flag over environment and file
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.physics.timestep, 0.125);
+        // Environment over file
+        assert_eq!(config.bind_address, "127.0.0.3");
+        // File over defaults
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.physics.gravitational_constant, 2.0);
+        // Defaults for everything else, including the rest of a partial [physics] table
+        assert_eq!(config.workers, defaults.workers);
+        assert_eq!(config.legacy_routes, defaults.legacy_routes);
+        assert_eq!(config.physics.integrator, defaults.physics.integrator);
+        assert_eq!(config.data_dir, defaults.data_dir);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for toml in ["prot = 8080\n", "[physics]\ntimestpe = 0.1\n"] {
+            let path = env::temp_dir().join(format!("multiverse-test-{}.toml", Uuid::new_v4()));
+            fs::write(&path, toml).unwrap();
+            let e = Config::from_file(Some(&path)).unwrap_err();
+            fs::remove_file(&path).unwrap();
+            assert!(e.contains("unknown field"), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,449 @@
+// The changes a node makes to its parent's universe before it starts ticking.
+// Each delta is either an explicit operation, tagged by "op", or the original BranchParams form,
+// which sets and offsets a body's state and creates the body if it doesn't exist yet.
+// Operations on a body that isn't there are skipped with a warning, as are ids that would collide.
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::{events::Detector, forces::Force, integrator::Integrator, motion::Motion, multiverse::BranchParams, orbit::{self, OrbitSpec}, simulation::{Body, Compensation, Physics, Pos, Universe}};
+
+#[derive(Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Delta {
+    Op(Operation),
+    Params(BranchParams),
+}
+
+// Picks the form by whether there's an "op", so a bad operation reports what's actually wrong with it
+impl<'de> Deserialize<'de> for Delta {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let delta = if value.get("op").is_some() {
+            Operation::deserialize(value).map(Delta::Op)
+        } else {
+            BranchParams::deserialize(value).map(Delta::Params)
+        };
+        delta.map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    AddBody {
+        // Defaults to a random id
+        id: Option<Uuid>,
+        mass: f64,
+        #[serde(default)]
+        position: Pos,
+        #[serde(default)]
+        velocity: Pos,
+        // Places the body on this orbit instead of at position and velocity
+        orbit: Option<OrbitSpec>,
+        name: Option<String>,
+        color: Option<String>,
+        radius: Option<f64>,
+        #[serde(default)]
+        properties: BTreeMap<String, serde_json::Value>,
+        #[serde(default)]
+        motion: Motion,
+        #[serde(default)]
+        test_particle: bool,
+    },
+    RemoveBody {
+        target_body: Uuid,
+    },
+    // Anything left out keeps its current value
+    SetState {
+        target_body: Uuid,
+        position: Option<Pos>,
+        velocity: Option<Pos>,
+        mass: Option<f64>,
+        orbit: Option<OrbitSpec>,
+        motion: Option<Motion>,
+        test_particle: Option<bool>,
+    },
+    OffsetState {
+        target_body: Uuid,
+        d_position: Option<Pos>,
+        d_velocity: Option<Pos>,
+        d_mass: Option<f64>,
+    },
+    ScaleMass {
+        target_body: Uuid,
+        factor: f64,
+    },
+    // Changes the body's momentum, so its velocity moves by impulse / mass
+    ApplyImpulse {
+        target_body: Uuid,
+        impulse: Pos,
+    },
+    Rename {
+        target_body: Uuid,
+        new_id: Uuid,
+    },
+    // Adds a force alongside those the universe already has
+    AddForce {
+        force: Force,
+    },
+    // Replaces all of the universe's forces. An empty list leaves plain Newtonian gravity.
+    SetForces {
+        forces: Vec<Force>,
+    },
+    // Switches the universe to adaptive steps within this tolerance, or back to fixed steps with None
+    SetTolerance {
+        tolerance: Option<f64>,
+    },
+    // Anything left out keeps its current value
+    SetIntegrator {
+        integrator: Option<Integrator>,
+        compensated: Option<bool>,
+    },
+    // Adds an event detector alongside those the universe already has
+    AddDetector {
+        detector: Detector,
+    },
+    // Replaces all of the universe's event detectors. An empty list stops looking for events.
+    SetDetectors {
+        detectors: Vec<Detector>,
+    },
+}
+
+impl From<BranchParams> for Delta {
+    fn from(params: BranchParams) -> Self {
+        Delta::Params(params)
+    }
+}
+
+impl From<Operation> for Delta {
+    fn from(op: Operation) -> Self {
+        Delta::Op(op)
+    }
+}
+
+impl Delta {
+    // Gives a body this delta would create with a random id a fixed one instead
+    pub fn assign_id(&mut self, new_id: impl FnOnce() -> Uuid) {
+        match self {
+            Delta::Params(params) if params.target_body.is_nil() => params.target_body = new_id(),
+            Delta::Op(Operation::AddBody { id, .. }) if id.is_none() => *id = Some(new_id()),
+            _ => {},
+        }
+    }
+
+    pub fn apply_universe(&self, target: &mut Universe) {
+        // Compensation carried for a body's old position or velocity would be wrong for whatever it's set to
+        let compensated: Vec<(Uuid, [u64; 6])> = target.bodies.iter()
+            .filter(|b| !b.compensation.is_zero())
+            .map(|b| (b.id, b.state_bits()))
+            .collect();
+        match self {
+            Delta::Op(op) => op.apply_universe(target),
+            Delta::Params(params) => params.apply_universe(target),
+        }
+        for body in &mut target.bodies {
+            if !body.compensation.is_zero() && !compensated.contains(&(body.id, body.state_bits())) {
+                body.compensation = Compensation::default();
+            }
+        }
+    }
+}
+
+impl Operation {
+    // The existing body the operation works on, None for those that don't work on one
+    pub fn target_body(&self) -> Option<Uuid> {
+        match *self {
+            Operation::AddBody { .. }
+            | Operation::AddForce { .. }
+            | Operation::SetForces { .. }
+            | Operation::SetTolerance { .. }
+            | Operation::SetIntegrator { .. }
+            | Operation::AddDetector { .. }
+            | Operation::SetDetectors { .. } => None,
+            Operation::RemoveBody { target_body }
+            | Operation::SetState { target_body, .. }
+            | Operation::OffsetState { target_body, .. }
+            | Operation::ScaleMass { target_body, .. }
+            | Operation::ApplyImpulse { target_body, .. }
+            | Operation::Rename { target_body, .. } => Some(target_body),
+        }
+    }
+
+    pub fn apply_universe(&self, target: &mut Universe) {
+        match self {
+            Operation::AddForce { force } => return add_forces(target, std::slice::from_ref(force), false),
+            Operation::SetForces { forces } => return add_forces(target, forces, true),
+            Operation::SetTolerance { tolerance } => return set_tolerance(target, *tolerance),
+            Operation::SetIntegrator { integrator, compensated } => return set_integrator(target, *integrator, *compensated),
+            Operation::AddDetector { detector } => return add_detectors(target, std::slice::from_ref(detector), false),
+            Operation::SetDetectors { detectors } => return add_detectors(target, detectors, true),
+            _ => {},
+        }
+        if let Operation::AddBody { id, mass, position, velocity, orbit, name, color, radius, properties, motion, test_particle } = self {
+            let id = id.unwrap_or_else(Uuid::new_v4);
+            if target.get_body(id).is_some() {
+                log::warn!("Body {} already exists, not adding it again", id);
+                return;
+            }
+            let mut body = Body { id, position: *position, velocity: *velocity, mass: *mass, test_particle: *test_particle, ..Default::default() };
+            body.set_metadata(name, color, *radius, properties);
+            target.add_body(body);
+            if let Some(orbit) = orbit {
+                if let Err(e) = orbit::place(target, target.bodies.len() - 1, orbit) {
+                    log::warn!("{}, leaving it in place", e);
+                }
+            }
+            set_motion(target, target.bodies.len() - 1, motion);
+            return;
+        }
+
+        let Some(target_body) = self.target_body() else {
+            return;
+        };
+        let Some(index) = target.bodies.iter().position(|b| b.id == target_body) else {
+            log::warn!("Body {} not found, skipping the delta", target_body);
+            return;
+        };
+        let body = &mut target.bodies[index];
+        match *self {
+            Operation::AddBody { .. }
+            | Operation::AddForce { .. }
+            | Operation::SetForces { .. }
+            | Operation::SetTolerance { .. }
+            | Operation::SetIntegrator { .. }
+            | Operation::AddDetector { .. }
+            | Operation::SetDetectors { .. } => {},
+            Operation::RemoveBody { target_body } => target.remove_body(&target_body),
+            Operation::SetState { position, velocity, mass, ref orbit, ref motion, test_particle, .. } => {
+                body.position = position.unwrap_or(body.position);
+                body.velocity = velocity.unwrap_or(body.velocity);
+                body.mass = mass.unwrap_or(body.mass);
+                body.test_particle = test_particle.unwrap_or(body.test_particle);
+                if let Some(orbit) = orbit {
+                    if let Err(e) = orbit::place(target, index, orbit) {
+                        log::warn!("{}, leaving it in place", e);
+                    }
+                }
+                if let Some(motion) = motion {
+                    set_motion(target, index, motion);
+                }
+            },
+            Operation::OffsetState { d_position, d_velocity, d_mass, .. } => {
+                body.position += d_position.unwrap_or_default();
+                body.velocity += d_velocity.unwrap_or_default();
+                body.mass += d_mass.unwrap_or_default();
+            },
+            Operation::ScaleMass { factor, .. } => {
+                if factor.is_finite() && factor >= 0.0 {
+                    body.mass *= factor;
+                } else {
+                    log::warn!("Mass scale factor must be a finite number no less than 0, got {}, ignoring it", factor);
+                }
+            },
+            Operation::ApplyImpulse { impulse, .. } => {
+                if body.mass > 0.0 {
+                    body.velocity += impulse * (1.0 / body.mass);
+                } else {
+                    log::warn!("Body {} is massless, ignoring the impulse", target_body);
+                }
+            },
+            Operation::Rename { new_id, .. } => {
+                if new_id != target_body && target.get_body(new_id).is_some() {
+                    log::warn!("Body {} already exists, not renaming {} to it", new_id, target_body);
+                } else {
+                    target.bodies[index].id = new_id;
+                }
+            },
+        }
+    }
+}
+
+// Logs and skips an invalid trajectory, like the other deltas that can't be applied
+pub fn set_motion(target: &mut Universe, index: usize, motion: &Motion) {
+    let time = target.time;
+    let body = &mut target.bodies[index];
+    if let Err(e) = body.set_motion(motion, time) {
+        log::warn!("{}, leaving body {} as it was", e, body.id);
+    }
+}
+
+// Adds forces to the universe, or replaces its own with them. Skipped with a warning if any of them is invalid,
+// or if they'd leave the universe with two models of gravity pulling at once.
+fn add_forces(target: &mut Universe, forces: &[Force], replace: bool) {
+    if let Some(e) = forces.iter().find_map(|f| f.model().validate().err()) {
+        log::warn!("{}, leaving the universe's forces as they were", e);
+        return;
+    }
+    let kept = if replace { &[][..] } else { &target.forces[..] };
+    if kept.iter().chain(forces).filter(|f| f.model().replaces_gravity()).count() > 1 {
+        log::warn!("Only one force can replace Newtonian gravity, leaving the universe's forces as they were");
+        return;
+    }
+    if replace {
+        target.forces.clear();
+    }
+    target.forces.extend(forces.iter().cloned());
+}
+
+// As add_forces, for event detectors
+fn add_detectors(target: &mut Universe, detectors: &[Detector], replace: bool) {
+    if let Some(e) = detectors.iter().find_map(|d| d.validate().err()) {
+        log::warn!("{}, leaving the universe's detectors as they were", e);
+        return;
+    }
+    if replace {
+        target.detectors.clear();
+    }
+    target.detectors.extend(detectors.iter().cloned());
+}
+
+fn set_tolerance(target: &mut Universe, tolerance: Option<f64>) {
+    if let Some(tolerance) = tolerance.filter(|t| !(t.is_finite() && *t > 0.0)) {
+        log::warn!("Tolerance must be a positive number, got {}, leaving the universe's as it was", tolerance);
+        return;
+    }
+    if let Err(e) = (Physics { tolerance, ..target.physics }).validate_stepping() {
+        log::warn!("{}, leaving the universe's tolerance as it was", e);
+        return;
+    }
+    target.physics.tolerance = tolerance;
+    // The last step size belonged to the old settings
+    target.step = None;
+}
+
+fn set_integrator(target: &mut Universe, integrator: Option<Integrator>, compensated: Option<bool>) {
+    let physics = Physics {
+        integrator: integrator.unwrap_or(target.physics.integrator),
+        compensated: compensated.unwrap_or(target.physics.compensated),
+        ..target.physics
+    };
+    if let Err(e) = physics.validate_stepping() {
+        log::warn!("{}, leaving the universe's integrator as it was", e);
+        return;
+    }
+    target.physics = physics;
+    if !target.physics.compensated {
+        for body in &mut target.bodies {
+            body.compensation = Compensation::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn universe() -> (Universe, Uuid, Uuid) {
+        let mut universe = Universe::default();
+        let (a, b) = (Body { mass: 2.0, ..Body::new() }, Body { mass: 0.0, position: Pos { x: 1.0, y: 0.0, z: 0.0 }, ..Body::new() });
+        let ids = (a.id, b.id);
+        universe.add_body(a);
+        universe.add_body(b);
+        (universe, ids.0, ids.1)
+    }
+
+    fn apply(universe: &mut Universe, op: Operation) {
+        Delta::from(op).apply_universe(universe);
+    }
+
+    #[test]
+    fn renaming_onto_an_existing_id_is_refused() {
+        let (mut universe, a, b) = universe();
+        apply(&mut universe, Operation::Rename { target_body: a, new_id: b });
+        assert_eq!(universe.bodies.iter().map(|b| b.id).collect::<Vec<_>>(), [a, b]);
+
+        let new_id = Uuid::new_v4();
+        apply(&mut universe, Operation::Rename { target_body: a, new_id });
+        assert_eq!(universe.bodies.iter().map(|b| b.id).collect::<Vec<_>>(), [new_id, b]);
+        assert_eq!(universe.bodies[0].mass, 2.0);
+    }
+
+    #[test]
+    fn impulses_change_velocity_by_impulse_over_mass_and_skip_massless_bodies() {
+        let (mut universe, a, b) = universe();
+        let impulse = Pos { x: 0.0, y: 4.0, z: 0.0 };
+        apply(&mut universe, Operation::ApplyImpulse { target_body: a, impulse });
+        apply(&mut universe, Operation::ApplyImpulse { target_body: b, impulse });
+        assert_eq!(universe.get_body(a).expect("Body should exist").velocity.y, 2.0);
+        assert!(universe.get_body(b).expect("Body should exist").velocity.is_zero());
+    }
+
+    #[test]
+    fn removing_a_body_leaves_the_others() {
+        let (mut universe, a, b) = universe();
+        apply(&mut universe, Operation::RemoveBody { target_body: a });
+        assert_eq!(universe.bodies.iter().map(|b| b.id).collect::<Vec<_>>(), [b]);
+        // Removing it again is skipped
+        apply(&mut universe, Operation::RemoveBody { target_body: a });
+        assert_eq!(universe.bodies.len(), 1);
+    }
+
+    #[test]
+    fn only_one_force_can_replace_gravity() {
+        let (mut universe, _, _) = universe();
+        let softened = || Force::SoftenedGravity(crate::forces::SoftenedGravity { softening: 0.1 });
+        apply(&mut universe, Operation::AddForce { force: softened() });
+        apply(&mut universe, Operation::AddForce { force: softened() });
+        assert_eq!(universe.forces.len(), 1);
+
+        apply(&mut universe, Operation::SetForces { forces: vec![softened(), softened()] });
+        assert_eq!(universe.forces.len(), 1);
+        // Replacing the one there is fine
+        apply(&mut universe, Operation::SetForces { forces: vec![Force::SoftenedGravity(crate::forces::SoftenedGravity { softening: 0.2 })] });
+        assert!(matches!(universe.forces[..], [Force::SoftenedGravity(f)] if f.softening == 0.2));
+    }
+
+    #[test]
+    fn compensation_is_reset_only_for_bodies_whose_state_changed() {
+        let (mut universe, a, b) = universe();
+        let carried = Compensation { position: Pos { x: 1e-17, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: -1e-17, z: 0.0 } };
+        for body in &mut universe.bodies {
+            body.compensation = carried;
+        }
+
+        // Mass and metadata don't touch the compensated sums
+        apply(&mut universe, Operation::ScaleMass { target_body: a, factor: 2.0 });
+        assert!(universe.bodies.iter().all(|b| !b.compensation.is_zero()));
+
+        apply(&mut universe, Operation::OffsetState { target_body: a, d_position: None, d_velocity: Some(Pos { x: 1.0, y: 0.0, z: 0.0 }), d_mass: None });
+        assert!(universe.get_body(a).expect("Body should exist").compensation.is_zero());
+        assert!(!universe.get_body(b).expect("Body should exist").compensation.is_zero());
+
+        // Setting a body to the state it already has changes nothing, down to the bit
+        let position = universe.get_body(b).expect("Body should exist").position;
+        apply(&mut universe, Operation::SetState { target_body: b, position: Some(position), velocity: None, mass: None, orbit: None, motion: None, test_particle: None });
+        assert!(!universe.get_body(b).expect("Body should exist").compensation.is_zero());
+        apply(&mut universe, Operation::SetState { target_body: b, position: Some(position * 2.0), velocity: None, mass: None, orbit: None, motion: None, test_particle: None });
+        assert!(universe.get_body(b).expect("Body should exist").compensation.is_zero());
+    }
+
+    #[test]
+    fn invalid_mass_factors_are_skipped() {
+        let (mut universe, a, _) = universe();
+        let mass = universe.get_body(a).expect("Body should exist").mass;
+        for factor in [-1.0, f64::NAN, f64::INFINITY] {
+            apply(&mut universe, Operation::ScaleMass { target_body: a, factor });
+            assert_eq!(universe.get_body(a).expect("Body should exist").mass, mass);
+        }
+        apply(&mut universe, Operation::ScaleMass { target_body: a, factor: 0.0 });
+        assert_eq!(universe.get_body(a).expect("Body should exist").mass, 0.0);
+    }
+
+    #[test]
+    fn a_tolerance_and_fixed_step_settings_are_never_combined() {
+        let (mut universe, _, _) = universe();
+        apply(&mut universe, Operation::SetTolerance { tolerance: Some(1e-9) });
+        apply(&mut universe, Operation::SetIntegrator { integrator: Some(Integrator::Yoshida4), compensated: Some(true) });
+        assert_eq!((universe.physics.integrator, universe.physics.compensated), (Integrator::default(), false));
+
+        // Back to fixed steps, which can then take the integrator, and then refuse a tolerance
+        apply(&mut universe, Operation::SetTolerance { tolerance: None });
+        apply(&mut universe, Operation::SetIntegrator { integrator: Some(Integrator::Yoshida4), compensated: None });
+        assert_eq!(universe.physics.integrator, Integrator::Yoshida4);
+        apply(&mut universe, Operation::SetTolerance { tolerance: Some(1e-9) });
+        assert_eq!(universe.physics.tolerance, None);
+    }
+}
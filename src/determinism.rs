@@ -0,0 +1,84 @@
+// Deterministic mode, switched on by Physics::deterministic. Node handles are derived from their parent's handle
+// and body, ensemble and sweep ids and seeds from the node they belong to, so replaying the same requests gives
+// the same multiverse. Each computed universe's content hash is recorded, and checked whenever it's computed again.
+use std::io;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::simulation::Universe;
+
+pub const HASH_STORE_FILE: &str = "universe_hashes.sqlite";
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct UniverseHash
+{
+    // Of the universe as first computed
+    pub hash: String,
+    // Hashes of later recomputes that didn't match, oldest first
+    #[serde(default)]
+    pub mismatches: Vec<String>,
+}
+
+// The same namespace and name always give the same id
+pub fn derive_id(namespace: Uuid, name: &str) -> Uuid {
+    Uuid::new_v5(&namespace, name.as_bytes())
+}
+
+pub fn derive_seed(namespace: Uuid, name: &str) -> u64 {
+    derive_id(namespace, name).as_u64_pair().0
+}
+
+// FNV-1a over the universe exactly as it's stored, so nothing persisted can change without changing the hash.
+// Stable across builds and platforms: serde_json writes fields in declaration order, properties sorted by key,
+// and every number as the shortest decimal that reads back to the same bits.
+pub fn content_hash(universe: &Universe) -> String {
+    let mut hasher = Fnv1a(0xcbf29ce484222325);
+    serde_json::to_writer(&mut hasher, universe).expect("Failed to serialize universe");
+    format!("{:016x}", hasher.0)
+}
+
+struct Fnv1a(u64);
+
+impl io::Write for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events::{Event, EventKind}, simulation::{Body, Pos}};
+
+    #[test]
+    fn the_hash_covers_everything_stored_and_survives_storage() {
+        let mut universe = Universe::new();
+        universe.add_body(Body { mass: 1.0, velocity: Pos { x: 0.1, y: 0.0, z: 0.0 }, ..Body::new() });
+        let hash = content_hash(&universe);
+        let stored: Universe = serde_json::from_str(&serde_json::to_string(&universe).unwrap()).unwrap();
+        assert_eq!(content_hash(&stored), hash);
+
+        let changes: [fn(&mut Universe); 5] = [
+            |u| u.ticks += 1,
+            |u| u.substeps += 1,
+            |u| u.step = Some(0.5),
+            |u| u.events.push(Event { tick: 1, time: 1.0, bodies: vec![], kind: EventKind::Escape { distance: 1.0, specific_energy: 0.5 } }),
+            |u| u.bodies[0].compensation.velocity.x = 1e-17,
+        ];
+        for change in changes {
+            let mut changed = universe.clone();
+            change(&mut changed);
+            assert_ne!(content_hash(&changed), hash);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Conserved quantities for checking whether a universe's physics is behaving.
+// In a closed system energy, momentum and angular momentum should stay constant between deltas,
+// so any drift comes from the integrator and timestep. Test particles are massless, so they're left out of
+// the conserved quantities and only counted. Pinned and driven bodies are held by something outside the
+// universe, so momentum isn't conserved once there are any. Energy still is with pinned bodies, but the work
+// driven bodies do shows up as energy drift. Potential energy comes from gravity and each of the universe's
+// forces, and forces without one, like drag, leave energy unconserved too.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{forces, handle::Handle, motion::Motion, multiverse::Multiverse, simulation::{Body, Pos, Universe}};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Diagnostics
+{
+    pub total_mass: f64,
+    pub kinetic_energy: f64,
+    pub potential_energy: f64,
+    pub total_energy: f64,
+    // Whether total_energy should stay constant between deltas: false once a force without a potential
+    // or a driven body is doing work on the universe
+    pub energy_conserved: bool,
+    pub linear_momentum: Pos,
+    // About the origin
+    pub angular_momentum: Pos,
+    pub center_of_mass: Pos,
+    pub center_of_mass_velocity: Pos,
+    // 2K / |U|, which is 1 for a bound system in equilibrium. None when there's no potential energy.
+    pub virial_ratio: Option<f64>,
+    pub test_particles: usize,
+    // Test particles with negative specific energy relative to the center of mass
+    pub bound_test_particles: usize,
+}
+
+impl Diagnostics {
+    pub fn new(universe: &Universe) -> Diagnostics {
+        let g = universe.physics.gravitational_constant;
+        let (particles, bodies): (Vec<&Body>, Vec<&Body>) = universe.bodies.iter().partition(|b| b.test_particle);
+
+        let total_mass: f64 = bodies.iter().map(|b| b.mass).sum();
+        let kinetic_energy: f64 = bodies.iter().map(|b| 0.5 * b.mass * b.velocity.dot(b.velocity)).sum();
+        let mut potential_energy = if universe.forces.iter().any(|f| f.model().replaces_gravity()) {
+            0.0
+        } else {
+            forces::newtonian_potential(universe, 0.0)
+        };
+        let mut energy_conserved = !universe.bodies.iter().any(|b| matches!(b.motion, Motion::Driven { .. }));
+        for force in &universe.forces {
+            match force.model().potential(universe) {
+                Some(potential) => potential_energy += potential,
+                None => energy_conserved = false,
+            }
+        }
+        let linear_momentum: Pos = bodies.iter().map(|b| b.velocity * b.mass).sum();
+        let angular_momentum: Pos = bodies.iter().map(|b| b.position.cross(b.velocity * b.mass)).sum();
+        let (center_of_mass, center_of_mass_velocity) = if total_mass != 0.0 {
+            (bodies.iter().map(|b| b.position * b.mass).sum::<Pos>() * (1.0 / total_mass), linear_momentum * (1.0 / total_mass))
+        } else {
+            (Pos::default(), Pos::default())
+        };
+
+        let bound_test_particles = particles.iter().filter(|p| {
+            let potential: f64 = bodies.iter()
+                .map(|b| (p.position.dist(b.position), b.mass))
+                .filter(|(r, _)| *r > 0.0)
+                .map(|(r, mass)| -g * mass / r)
+                .sum();
+            let v = p.velocity - center_of_mass_velocity;
+            0.5 * v.dot(v) + potential < 0.0
+        }).count();
+
+        Diagnostics {
+            total_mass,
+            kinetic_energy,
+            potential_energy,
+            total_energy: kinetic_energy + potential_energy,
+            energy_conserved,
+            linear_momentum,
+            angular_momentum,
+            center_of_mass,
+            center_of_mass_velocity,
+            virial_ratio: if potential_energy != 0.0 { Some(2.0 * kinetic_energy / potential_energy.abs()) } else { None },
+            test_particles: particles.len(),
+            bound_test_particles,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DiagnosticsSample
+{
+    pub node: Handle,
+    // Ticks since the root
+    pub age: i64,
+    pub diagnostics: Diagnostics,
+    // (E - E0) / |E0|, where E0 is the energy just after this node's deltas were applied.
+    // Measured per node so perturbations don't show up as integrator drift.
+    pub relative_energy_error: f64,
+}
+
+// Samples the diagnostics along the timeline from the root to handle. Each node contributes a sample
+// just after its deltas are applied, one every `every` ticks if given, and one at its end.
+pub fn track(multiverse: &Multiverse, handle: &Handle, every: Option<i32>) -> Option<Vec<DiagnosticsSample>> {
+    let mut samples = vec![];
+    let mut age: i64 = 0;
+    for node_handle in multiverse.lineage(handle)? {
+        let node = multiverse.nodes.get(&node_handle)?;
+        let mut universe = node.initial_universe(multiverse);
+        let start_energy = Diagnostics::new(&universe).total_energy;
+        let mut sample = |universe: &Universe, age: i64| {
+            let diagnostics = Diagnostics::new(universe);
+            let relative_energy_error = if start_energy != 0.0 {
+                (diagnostics.total_energy - start_energy) / start_energy.abs()
+            } else {
+                0.0
+            };
+            samples.push(DiagnosticsSample { node: node_handle, age, diagnostics, relative_energy_error });
+        };
+
+        sample(&universe, age);
+        for tick in 1..=node.relative_age {
+            universe.tick();
+            let at_end = tick == node.relative_age;
+            if at_end || every.is_some_and(|n| n > 0 && tick % n == 0) {
+                sample(&universe, age + tick as i64);
+            }
+        }
+        age += node.relative_age.max(0) as i64;
+    }
+    Some(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{forces::{Drag, Force, SoftenedGravity, UniformField}, integrator::Integrator, simulation::Physics};
+
+    #[test]
+    fn a_two_body_orbit_conserves_energy_and_momentum() {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, timestep: 1e-3, ..Default::default() });
+        // Circular orbit of the pair about their center of mass, which starts at rest
+        let (m1, m2): (f64, f64) = (1.0, 0.3);
+        let speed = (1.0 / (m1 + m2)).sqrt();
+        universe.add_body(Body { mass: m1, velocity: Pos { x: 0.0, y: -speed * m2, z: 0.0 }, ..Body::new() });
+        universe.add_body(Body { mass: m2, position: Pos { x: 1.0, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: speed * m1, z: 0.0 }, ..Body::new() });
+        let before = Diagnostics::new(&universe);
+
+        // About one orbital period
+        universe.tick_for(5500);
+        let after = Diagnostics::new(&universe);
+        assert!(((after.total_energy - before.total_energy) / before.total_energy).abs() < 1e-3);
+        assert!((after.linear_momentum - before.linear_momentum).length() < 1e-12);
+        assert!((after.angular_momentum - before.angular_momentum).length() < 1e-3 * before.angular_momentum.length());
+        // Still bound at about the same separation, which a wrong force law wouldn't manage
+        let separation = universe.bodies[0].position.dist(universe.bodies[1].position);
+        assert!((separation - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn softened_gravity_conserves_its_own_potential_energy() {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, timestep: 1e-3, integrator: Integrator::Yoshida4, ..Default::default() });
+        universe.forces = vec![Force::SoftenedGravity(SoftenedGravity { softening: 0.5 })];
+        // An eccentric orbit whose closest approach is well inside the softening length
+        universe.add_body(Body { mass: 1.0, velocity: Pos { x: 0.0, y: -0.1, z: 0.0 }, ..Body::new() });
+        universe.add_body(Body { mass: 1.0, position: Pos { x: 1.0, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 0.1, z: 0.0 }, ..Body::new() });
+        let before = Diagnostics::new(&universe);
+        assert!(before.energy_conserved);
+        assert!((before.potential_energy + 1.0 / 1.25f64.sqrt()).abs() < 1e-15);
+
+        let newtonian_energy = |u: &Universe| Diagnostics::new(u).kinetic_energy + forces::newtonian_potential(u, 0.0);
+        let newtonian_before = newtonian_energy(&universe);
+        let mut worst: f64 = 0.0;
+        let mut newtonian_worst: f64 = 0.0;
+        for _ in 0..3000 {
+            universe.tick();
+            worst = worst.max((Diagnostics::new(&universe).total_energy - before.total_energy).abs());
+            newtonian_worst = newtonian_worst.max((newtonian_energy(&universe) - newtonian_before).abs());
+        }
+        assert!(worst < 1e-9 * before.total_energy.abs(), "{}", worst);
+        // The unsoftened formula doesn't describe this universe at all
+        assert!(newtonian_worst > 0.1 * before.total_energy.abs(), "{}", newtonian_worst);
+    }
+
+    #[test]
+    fn forces_without_a_potential_mark_energy_unconserved() {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 0.0, timestep: 1e-2, integrator: Integrator::Yoshida4, ..Default::default() });
+        universe.forces = vec![Force::UniformField(UniformField { acceleration: Pos { x: 0.0, y: 0.0, z: -9.8 } })];
+        universe.add_body(Body { mass: 2.0, velocity: Pos { x: 1.0, y: 0.0, z: 5.0 }, ..Body::new() });
+        let before = Diagnostics::new(&universe);
+        universe.tick_for(100);
+        let after = Diagnostics::new(&universe);
+        assert!(before.energy_conserved);
+        assert!((after.total_energy - before.total_energy).abs() < 1e-9 * before.total_energy.abs());
+
+        universe.forces.push(Force::Drag(Drag { linear: 0.1, quadratic: 0.0, medium_velocity: Pos::default() }));
+        assert!(!Diagnostics::new(&universe).energy_conserved);
+    }
+}
@@ -0,0 +1,106 @@
+// Compares two universes body by body, to measure how far one branch has drifted from another.
+// Bodies are matched by id, so a branch only lines up with its sibling for bodies they both inherited.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::simulation::{Pos, Universe};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct BodyDiff
+{
+    pub id: Uuid,
+    // Each difference is b - a
+    pub position: Pos,
+    pub velocity: Pos,
+    pub mass: f64,
+    // Lengths of the position and velocity differences
+    pub distance: f64,
+    pub speed: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UniverseDiff
+{
+    // Bodies in both universes, in a's order
+    pub bodies: Vec<BodyDiff>,
+    pub only_in_a: Vec<Uuid>,
+    pub only_in_b: Vec<Uuid>,
+    // Root mean square over the bodies in both universes, zero when there are none
+    pub rms_position_divergence: f64,
+    pub rms_velocity_divergence: f64,
+    pub max_position_divergence: f64,
+}
+
+impl UniverseDiff {
+    pub fn new(a: &Universe, b: &Universe) -> UniverseDiff {
+        let bodies: Vec<BodyDiff> = a.bodies.iter().filter_map(|body_a| {
+            let body_b = b.get_body(body_a.id)?;
+            let position = body_b.position - body_a.position;
+            let velocity = body_b.velocity - body_a.velocity;
+            Some(BodyDiff {
+                id: body_a.id,
+                position,
+                velocity,
+                mass: body_b.mass - body_a.mass,
+                distance: position.length(),
+                speed: velocity.length(),
+            })
+        }).collect();
+        let rms = |values: Vec<f64>| if values.is_empty() {
+            0.0
+        } else {
+            (values.iter().map(|v| v * v).sum::<f64>() / values.len() as f64).sqrt()
+        };
+
+        UniverseDiff {
+            only_in_a: a.bodies.iter().filter(|body| b.get_body(body.id).is_none()).map(|body| body.id).collect(),
+            only_in_b: b.bodies.iter().filter(|body| a.get_body(body.id).is_none()).map(|body| body.id).collect(),
+            rms_position_divergence: rms(bodies.iter().map(|d| d.distance).collect()),
+            rms_velocity_divergence: rms(bodies.iter().map(|d| d.speed).collect()),
+            max_position_divergence: bodies.iter().map(|d| d.distance).fold(0.0, f64::max),
+            bodies,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Body;
+
+    fn universe() -> Universe {
+        let mut universe = Universe::default();
+        universe.add_body(Body { mass: 1.0, ..Body::new() });
+        universe.add_body(Body { mass: 2.0, position: Pos { x: 1.0, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 1.0, z: 0.0 }, ..Body::new() });
+        universe
+    }
+
+    #[test]
+    fn identical_universes_have_no_differences() {
+        let a = universe();
+        let diff = UniverseDiff::new(&a, &a.clone());
+        assert_eq!(diff.bodies.iter().map(|d| d.id).collect::<Vec<_>>(), a.bodies.iter().map(|b| b.id).collect::<Vec<_>>());
+        assert!(diff.bodies.iter().all(|d| d.position.is_zero() && d.velocity.is_zero() && d.mass == 0.0));
+        assert!(diff.only_in_a.is_empty() && diff.only_in_b.is_empty());
+        assert_eq!((diff.rms_position_divergence, diff.rms_velocity_divergence, diff.max_position_divergence), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn bodies_are_matched_by_id() {
+        let a = universe();
+        let mut b = a.clone();
+        b.bodies[1].position.x += 3.0;
+        b.bodies[1].velocity.z -= 4.0;
+        b.bodies.remove(0);
+        b.add_body(Body::new());
+
+        let diff = UniverseDiff::new(&a, &b);
+        assert_eq!(diff.bodies.len(), 1);
+        assert_eq!(diff.bodies[0].id, a.bodies[1].id);
+        assert_eq!((diff.bodies[0].distance, diff.bodies[0].speed), (3.0, 4.0));
+        assert_eq!(diff.only_in_a, [a.bodies[0].id]);
+        assert_eq!(diff.only_in_b, [b.bodies[1].id]);
+        assert_eq!((diff.rms_position_divergence, diff.max_position_divergence), (3.0, 3.0));
+    }
+}
@@ -0,0 +1,223 @@
+// Sensitivity analysis between two branches. Both are replayed tick by tick from the end of their
+// most recent common ancestor, applying each node's deltas as its start comes round, and compared as they go.
+// The maximal Lyapunov exponent comes from a least squares fit of ln(divergence) against age,
+// taken after both branches have had all their deltas and before they decorrelate.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{diagnostics::Diagnostics, handle::Handle, delta::Delta, multiverse::Multiverse, simulation::Universe};
+
+// Fraction of the system's size the branches must drift apart by to count as decorrelated
+pub const DEFAULT_THRESHOLD: f64 = 0.5;
+
+// Replays run on the multiverse's thread, so keep one from holding it up indefinitely
+pub const MAX_DURATION: i32 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DivergenceOptions
+{
+    // Ticks to replay past the common ancestor, defaulting to the longer branch. Either way at most MAX_DURATION.
+    pub duration: Option<i32>,
+    // Sample every this many ticks, defaulting to every tick
+    pub every: Option<i32>,
+    // Defaults to DEFAULT_THRESHOLD
+    pub threshold: Option<f64>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DivergenceSample
+{
+    // Ticks since the end of the common ancestor
+    pub age: i64,
+    // RMS over the bodies both branches share
+    pub position_divergence: f64,
+    pub velocity_divergence: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DivergenceReport
+{
+    pub ancestor: Handle,
+    pub samples: Vec<DivergenceSample>,
+    // Per tick. None when there weren't enough growing samples to fit.
+    pub lyapunov_exponent: Option<f64>,
+    // Ticks for the divergence to grow by a factor of e, 1 / lyapunov_exponent
+    pub lyapunov_time: Option<f64>,
+    // First sampled age at which the position divergence reached the threshold, None if it never did
+    pub decorrelation_age: Option<i64>,
+    // In the same units as position_divergence, taken from the size of the first branch at the start
+    pub decorrelation_threshold: f64,
+}
+
+// The last node both lineages share
+pub fn common_ancestor(multiverse: &Multiverse, a: &Handle, b: &Handle) -> Option<Handle> {
+    let lineage_a = multiverse.lineage(a)?;
+    let lineage_b = multiverse.lineage(b)?;
+    lineage_a.iter().zip(&lineage_b).take_while(|(x, y)| x == y).last().map(|(x, _)| *x)
+}
+
+// RMS distance of the bodies from their center of mass
+fn size(universe: &Universe) -> f64 {
+    if universe.bodies.is_empty() {
+        return 0.0;
+    }
+    let center = Diagnostics::new(universe).center_of_mass;
+    (universe.bodies.iter().map(|b| b.position.dist_sq(center)).sum::<f64>() / universe.bodies.len() as f64).sqrt()
+}
+
+// A branch being replayed from the ancestor: its universe, and the deltas still to come with the ages they land at
+struct Replay<'a> {
+    universe: Universe,
+    pending: Vec<(i64, &'a Vec<Delta>)>,
+    // Age of the branch's last node's start, after which nothing more is applied
+    settled_at: i64,
+    end: i64,
+}
+
+impl<'a> Replay<'a> {
+    fn new(multiverse: &'a Multiverse, start: &Universe, ancestor: &Handle, node: &Handle) -> Option<Replay<'a>> {
+        let lineage = multiverse.lineage(node)?;
+        let position = lineage.iter().position(|h| h == ancestor)?;
+        let mut pending = vec![];
+        let mut age: i64 = 0;
+        let mut settled_at = 0;
+        for handle in &lineage[position + 1..] {
+            let node = multiverse.nodes.get(handle)?;
+            if let Some(delta) = &node.delta {
+                pending.push((age, delta));
+            }
+            settled_at = age;
+            age += node.relative_age.max(0) as i64;
+        }
+        pending.reverse();
+        Some(Replay { universe: start.clone(), pending, settled_at, end: age })
+    }
+
+    // Applies every delta due at age
+    fn apply(&mut self, age: i64) {
+        while self.pending.last().is_some_and(|(at, _)| *at == age) {
+            if let Some((_, delta)) = self.pending.pop() {
+                for param in delta {
+                    param.apply_universe(&mut self.universe);
+                }
+            }
+        }
+    }
+}
+
+// Replays a and b past their common ancestor, sampling their divergence as configured and at the end.
+// None if either node doesn't exist or they don't share a root.
+pub fn track(multiverse: &Multiverse, a: &Handle, b: &Handle, options: DivergenceOptions) -> Option<DivergenceReport> {
+    let ancestor = common_ancestor(multiverse, a, b)?;
+    let start = multiverse.nodes.get(&ancestor)?.get_universe(multiverse);
+    let mut replay_a = Replay::new(multiverse, &start, &ancestor, a)?;
+    let mut replay_b = Replay::new(multiverse, &start, &ancestor, b)?;
+    let horizon = match options.duration {
+        Some(duration) => duration.max(0) as i64,
+        None => replay_a.end.max(replay_b.end),
+    }.min(MAX_DURATION as i64);
+    let every = options.every.unwrap_or(1).max(1) as i64;
+    let decorrelation_threshold = options.threshold.unwrap_or(DEFAULT_THRESHOLD) * size(&start);
+
+    let mut samples = vec![];
+    for age in 0..=horizon {
+        replay_a.apply(age);
+        replay_b.apply(age);
+        if age % every == 0 || age == horizon {
+            let diff = replay_a.universe.diff(&replay_b.universe);
+            samples.push(DivergenceSample {
+                age,
+                position_divergence: diff.rms_position_divergence,
+                velocity_divergence: diff.rms_velocity_divergence,
+            });
+        }
+        if age < horizon {
+            replay_a.universe.tick();
+            replay_b.universe.tick();
+        }
+    }
+
+    let decorrelation_age = samples.iter()
+        .find(|s| decorrelation_threshold > 0.0 && s.position_divergence >= decorrelation_threshold)
+        .map(|s| s.age);
+    let settled_at = replay_a.settled_at.max(replay_b.settled_at);
+    let growth: Vec<(f64, f64)> = samples.iter()
+        .filter(|s| s.age >= settled_at && s.position_divergence > 0.0)
+        .filter(|s| decorrelation_age.is_none_or(|d| s.age < d))
+        .map(|s| (s.age as f64, s.position_divergence.ln()))
+        .collect();
+    let lyapunov_exponent = slope(&growth);
+
+    Some(DivergenceReport {
+        ancestor,
+        samples,
+        lyapunov_exponent,
+        lyapunov_time: lyapunov_exponent.filter(|l| *l > 0.0).map(|l| 1.0 / l),
+        decorrelation_age,
+        decorrelation_threshold,
+    })
+}
+
+// Least squares slope of y against x
+fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let variance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.0 - mean_x)).sum();
+    if variance == 0.0 { None } else { Some(covariance / variance) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{delta::Operation, scenario::{FigureEight, Scenario}, simulation::{Physics, Pos}};
+
+    #[test]
+    fn slope_fits_a_line() {
+        let points: Vec<(f64, f64)> = (0..10).map(|x| (x as f64, 0.25 * x as f64 - 3.0)).collect();
+        assert!((slope(&points).expect("Should fit") - 0.25).abs() < 1e-12);
+        assert_eq!(slope(&points[..1]), None);
+        assert_eq!(slope(&[(1.0, 0.0), (1.0, 2.0)]), None);
+    }
+
+    #[test]
+    fn only_a_perturbed_branch_diverges() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::FigureEight(FigureEight::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let body = multiverse.get_universe(&root).expect("Root should exist").bodies[0].id;
+
+        let a = multiverse.branch(&root, 200, vec![]).expect("Root should exist");
+        let b = multiverse.branch(&root, 200, vec![]).expect("Root should exist");
+        let offset = Operation::OffsetState { target_body: body, d_position: Some(Pos { x: 1e-6, y: 0.0, z: 0.0 }), d_velocity: None, d_mass: None };
+        let perturbed = multiverse.branch(&root, 200, vec![offset.into()]).expect("Root should exist");
+        assert_eq!(common_ancestor(&multiverse, &a, &b), Some(root));
+
+        let options = DivergenceOptions { every: Some(50), ..Default::default() };
+        let report = track(&multiverse, &a, &b, options).expect("Both nodes share the root");
+        assert_eq!(report.ancestor, root);
+        assert_eq!(report.samples.iter().map(|s| s.age).collect::<Vec<_>>(), [0, 50, 100, 150, 200]);
+        assert!(report.samples.iter().all(|s| s.position_divergence == 0.0 && s.velocity_divergence == 0.0));
+        assert_eq!((report.lyapunov_exponent, report.decorrelation_age), (None, None));
+
+        let report = track(&multiverse, &a, &perturbed, options).expect("Both nodes share the root");
+        // Three bodies, one of them moved
+        let first = report.samples[0].position_divergence;
+        assert!((first - 1e-6 / 3f64.sqrt()).abs() < 1e-12);
+        assert!(report.samples.last().expect("Should sample the end").position_divergence > first);
+        assert!(report.lyapunov_exponent.is_some_and(|l| l > 0.0));
+        assert_eq!(report.decorrelation_age, None);
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+}
@@ -0,0 +1,326 @@
+// Monte Carlo ensembles: many children of one node, each nudged by random d_position, d_velocity and d_mas
+// deltas. Every draw comes from one ChaCha8 stream seeded with the ensemble's seed, in member order, so the
+// same seed and spec always give the same ensemble. The seed is recorded on every member.
+use std::{collections::HashMap, f64::consts::PI, fmt};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{handle::Handle, multiverse::{BranchParams, Multiverse}, simulation::{Pos, Universe}};
+
+pub const MAX_MEMBERS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Distribution {
+    Gaussian { mean: f64, std_dev: f64 },
+    // Drawn from [min, max)
+    Uniform { min: f64, max: f64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PerturbedField {
+    DPosition,
+    DVelocity,
+    DMas,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Perturbation
+{
+    // Defaults to every body in the node's universe
+    pub target_body: Option<Uuid>,
+    pub field: PerturbedField,
+    // Vector fields draw each component independently
+    pub distribution: Distribution,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Ensemble
+{
+    pub count: usize,
+    // Defaults to a random seed, which is reported back and recorded on every member
+    pub seed: Option<u64>,
+    pub perturbations: Vec<Perturbation>,
+}
+
+// Marks a node as one member of an ensemble
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EnsembleTag
+{
+    pub ensemble: Uuid,
+    pub seed: u64,
+    pub member: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EnsembleCreated
+{
+    pub ensemble: Uuid,
+    pub seed: u64,
+    // In member order
+    pub members: Vec<Handle>,
+}
+
+#[derive(Debug)]
+pub enum EnsembleError {
+    NotFound,
+    // A perturbation targets a body the node's universe doesn't have
+    UnknownBody(Uuid),
+}
+
+impl fmt::Display for EnsembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnsembleError::NotFound => write!(f, "Node not found"),
+            EnsembleError::UnknownBody(id) => write!(f, "Body {} not found in the node's universe", id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct BodyStatistics
+{
+    pub id: Uuid,
+    // How many members the body appears in
+    pub members: usize,
+    pub mean_position: Pos,
+    // Per component, using the sample variance. Zero with a single member.
+    pub position_variance: Pos,
+    pub mean_velocity: Pos,
+    pub velocity_variance: Pos,
+    pub mean_mass: f64,
+    pub mass_variance: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EnsembleStatistics
+{
+    pub ensemble: Uuid,
+    pub seed: u64,
+    pub members: usize,
+    // Ticks since the members branched
+    pub age: i32,
+    pub bodies: Vec<BodyStatistics>,
+}
+
+impl Distribution {
+    fn sample(&self, rng: &mut ChaCha8Rng) -> f64 {
+        match *self {
+            // Box-Muller
+            Distribution::Gaussian { mean, std_dev } => {
+                let u: f64 = rng.random_range(f64::EPSILON..1.0);
+                let v: f64 = rng.random_range(0.0..1.0);
+                mean + std_dev * (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+            },
+            Distribution::Uniform { min, max } if min == max => min,
+            Distribution::Uniform { min, max } => rng.random_range(min..max),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            Distribution::Gaussian { mean, std_dev } if mean.is_finite() && std_dev.is_finite() && std_dev >= 0.0 => Ok(()),
+            Distribution::Gaussian { .. } => Err(String::from("A gaussian needs a finite mean and a non-negative std_dev")),
+            Distribution::Uniform { min, max } if min.is_finite() && max.is_finite() && min <= max => Ok(()),
+            Distribution::Uniform { .. } => Err(String::from("A uniform distribution needs finite bounds with min <= max")),
+        }
+    }
+}
+
+impl Ensemble {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.count == 0 || self.count > MAX_MEMBERS {
+            return Err(format!("An ensemble needs between 1 and {} members", MAX_MEMBERS));
+        }
+        self.perturbations.iter().try_for_each(|p| p.distribution.validate())
+    }
+
+    // Each member's deltas against the universe they branch from, and the seed they were drawn with.
+    // Every target_body must be in the universe, since a delta for a missing body would create it.
+    pub fn members(&self, universe: &Universe) -> Result<(u64, Vec<Vec<BranchParams>>), EnsembleError> {
+        if let Some(id) = self.perturbations.iter().filter_map(|p| p.target_body).find(|id| universe.get_body(*id).is_none()) {
+            return Err(EnsembleError::UnknownBody(id));
+        }
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let members = (0..self.count).map(|_| {
+            let mut deltas: Vec<BranchParams> = vec![];
+            for perturbation in &self.perturbations {
+                let targets = match perturbation.target_body {
+                    Some(id) => vec![id],
+                    None => universe.bodies.iter().map(|b| b.id).collect(),
+                };
+                for target_body in targets {
+                    let index = match deltas.iter().position(|d| d.target_body == target_body) {
+                        Some(i) => i,
+                        None => {
+                            deltas.push(BranchParams { target_body, ..Default::default() });
+                            deltas.len() - 1
+                        },
+                    };
+                    let delta = &mut deltas[index];
+                    let mut draw = || perturbation.distribution.sample(&mut rng);
+                    match perturbation.field {
+                        PerturbedField::DPosition => delta.d_position = Some(delta.d_position.unwrap_or_default() + Pos{ x: draw(), y: draw(), z: draw() }),
+                        PerturbedField::DVelocity => delta.d_velocity = Some(delta.d_velocity.unwrap_or_default() + Pos{ x: draw(), y: draw(), z: draw() }),
+                        PerturbedField::DMas => delta.d_mas = Some(delta.d_mas.unwrap_or_default() + draw()),
+                    }
+                }
+            }
+            deltas
+        }).collect();
+        Ok((seed, members))
+    }
+}
+
+// (position, velocity, mass)
+type State = (Pos, Pos, f64);
+
+// Mean and sample variance
+fn moments(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let n = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / n;
+    let variance = if n > 1.0 { values.map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1.0) } else { 0.0 };
+    (mean, variance)
+}
+
+fn vector_moments(values: &[Pos]) -> (Pos, Pos) {
+    let (x, var_x) = moments(values.iter().map(|p| p.x));
+    let (y, var_y) = moments(values.iter().map(|p| p.y));
+    let (z, var_z) = moments(values.iter().map(|p| p.z));
+    (Pos{ x, y, z }, Pos{ x: var_x, y: var_y, z: var_z })
+}
+
+// Statistics of each body across the ensemble `age` ticks after branching, defaulting to each member's own length.
+// None if no node belongs to the ensemble.
+pub fn statistics(multiverse: &Multiverse, ensemble: Uuid, age: Option<i32>) -> Option<EnsembleStatistics> {
+    let mut members: Vec<(EnsembleTag, Handle)> = multiverse.nodes.iter()
+        .filter_map(|(handle, node)| Some((node.ensemble.filter(|t| t.ensemble == ensemble)?, *handle)))
+        .collect();
+    members.sort_by_key(|(tag, _)| tag.member);
+    let (first, _) = *members.first()?;
+
+    // (id, states in member order), kept in the order bodies were first seen
+    let mut bodies: Vec<(Uuid, Vec<State>)> = vec![];
+    let mut index: HashMap<Uuid, usize> = HashMap::new();
+    let mut reported_age = 0;
+    for (_, handle) in &members {
+        let node = multiverse.nodes.get(handle)?;
+        let universe = match age {
+            Some(age) => {
+                let mut universe = node.initial_universe(multiverse);
+                universe.tick_for(age);
+                reported_age = age;
+                universe
+            },
+            None => {
+                reported_age = node.relative_age;
+                node.get_universe(multiverse)
+            },
+        };
+        for body in universe.bodies {
+            let i = *index.entry(body.id).or_insert_with(|| {
+                bodies.push((body.id, vec![]));
+                bodies.len() - 1
+            });
+            bodies[i].1.push((body.position, body.velocity, body.mass));
+        }
+    }
+
+    Some(EnsembleStatistics {
+        ensemble,
+        seed: first.seed,
+        members: members.len(),
+        age: reported_age,
+        bodies: bodies.into_iter().map(|(id, states)| {
+            let positions: Vec<Pos> = states.iter().map(|s| s.0).collect();
+            let velocities: Vec<Pos> = states.iter().map(|s| s.1).collect();
+            let (mean_position, position_variance) = vector_moments(&positions);
+            let (mean_velocity, velocity_variance) = vector_moments(&velocities);
+            let (mean_mass, mass_variance) = moments(states.iter().map(|s| s.2));
+            BodyStatistics {
+                id,
+                members: states.len(),
+                mean_position,
+                position_variance,
+                mean_velocity,
+                velocity_variance,
+                mean_mass,
+                mass_variance,
+            }
+        }).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{delta::Delta, simulation::Body};
+
+    fn universe() -> Universe {
+        let mut universe = Universe::default();
+        for x in 0..3 {
+            universe.add_body(Body { mass: 1.0, position: Pos { x: x as f64, y: 0.0, z: 0.0 }, ..Body::new() });
+        }
+        universe
+    }
+
+    fn ensemble(target_body: Option<Uuid>) -> Ensemble {
+        Ensemble {
+            count: 4,
+            seed: Some(7),
+            perturbations: vec![
+                Perturbation { target_body, field: PerturbedField::DPosition, distribution: Distribution::Gaussian { mean: 0.0, std_dev: 0.1 } },
+                Perturbation { target_body, field: PerturbedField::DMas, distribution: Distribution::Uniform { min: 0.1, max: 0.5 } },
+            ],
+        }
+    }
+
+    fn apply(universe: &Universe, deltas: &[BranchParams]) -> Universe {
+        let mut universe = universe.clone();
+        for delta in deltas {
+            Delta::from(delta.clone()).apply_universe(&mut universe);
+        }
+        universe
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_members() {
+        let universe = universe();
+        let (seed, first) = ensemble(None).members(&universe).expect("Every target exists");
+        let (_, second) = ensemble(None).members(&universe).expect("Every target exists");
+        assert_eq!(seed, 7);
+        assert_eq!(serde_json::to_value(&first).unwrap(), serde_json::to_value(&second).unwrap());
+        // But each member gets its own draws
+        assert_ne!(serde_json::to_value(&first[0]).unwrap(), serde_json::to_value(&first[1]).unwrap());
+    }
+
+    #[test]
+    fn members_only_perturb_their_target() {
+        let universe = universe();
+        let target = universe.bodies[1].id;
+        let (_, members) = ensemble(Some(target)).members(&universe).expect("Every target exists");
+        assert_eq!(members.len(), 4);
+        for deltas in &members {
+            let member = apply(&universe, deltas);
+            assert_eq!(member.bodies.len(), universe.bodies.len());
+            for (before, after) in universe.bodies.iter().zip(&member.bodies) {
+                assert_eq!(before.id, after.id);
+                let moved = before.position.dist(after.position) > 0.0 && after.mass > before.mass;
+                let unchanged = before.position.dist(after.position) == 0.0 && after.mass == before.mass;
+                assert!(if before.id == target { moved } else { unchanged });
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_targets_are_rejected() {
+        let missing = Uuid::new_v4();
+        assert!(matches!(ensemble(Some(missing)).members(&universe()), Err(EnsembleError::UnknownBody(id)) if id == missing));
+    }
+}
@@ -0,0 +1,203 @@
+// Things worth knowing about that happen inside a branch: close approaches, escapes, apsis passages and collisions.
+// Each universe carries the detectors to look for them with, which its children inherit and deltas can change,
+// like its forces. What they find is recorded on the universe, so a node's universe holds the events of that node's
+// own ticks. Everything is judged between the states at the start and end of each tick, so passages are caught
+// however quickly they happen, but the distances reported are only as good as the timestep.
+// Pairs of test particles are never checked against each other, which keeps swarms of them affordable.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{orbit, simulation::{Body, Pos, Universe}};
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Detector {
+    // Pairs passing closer than distance, recorded as they go through their closest approach
+    CloseApproach { distance: f64 },
+    // Bodies further than radius from the massive bodies' center of mass, with the energy to leave for good
+    Escape { radius: f64 },
+    // Periapsis and apoapsis passages around primary, or around each body's dominant attractor if it's left out
+    Apsides {
+        #[serde(default)]
+        primary: Option<Uuid>,
+    },
+    // Bodies with radii coming into contact
+    Collision,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Event
+{
+    // The node's tick it happened in, counting from 1
+    pub tick: u64,
+    // The universe's time at the end of that tick
+    pub time: f64,
+    // Two bodies for close approaches, apsides (secondary first) and collisions, one for escapes
+    pub bodies: Vec<Uuid>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    // Smallest separation, estimated from the relative motion at the start of the tick
+    CloseApproach { distance: f64, relative_speed: f64 },
+    // Specific energy relative to the massive bodies' center of mass
+    Escape { distance: f64, specific_energy: f64 },
+    Periapsis { distance: f64 },
+    Apoapsis { distance: f64 },
+    Collision { distance: f64, relative_speed: f64 },
+}
+
+impl EventKind {
+    // As it appears in the "kind" field
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::CloseApproach { .. } => "close_approach",
+            EventKind::Escape { .. } => "escape",
+            EventKind::Periapsis { .. } => "periapsis",
+            EventKind::Apoapsis { .. } => "apoapsis",
+            EventKind::Collision { .. } => "collision",
+        }
+    }
+}
+
+impl Detector {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Detector::CloseApproach { distance } if !(distance.is_finite() && *distance > 0.0) => {
+                Err(String::from("A close approach detector needs a positive distance"))
+            },
+            Detector::Escape { radius } if !(radius.is_finite() && *radius >= 0.0) => Err(String::from("An escape detector needs a non-negative radius")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Position and velocity of every body
+pub type State = Vec<(Pos, Pos)>;
+
+pub fn state(universe: &Universe) -> State {
+    universe.bodies.iter().map(|b| (b.position, b.velocity)).collect()
+}
+
+// The events of the tick that took the universe from before to where it is now, which has the same bodies
+pub fn detect(universe: &Universe, before: &[(Pos, Pos)]) -> Vec<Event> {
+    let after = state(universe);
+    let mut found = vec![];
+    let mut record = |bodies: Vec<Uuid>, kind: EventKind| found.push(Event { tick: universe.ticks, time: universe.time, bodies, kind });
+    for detector in &universe.detectors {
+        match detector {
+            Detector::CloseApproach { distance } => {
+                for (i, j) in pairs(&universe.bodies) {
+                    let (r0, v0) = relative(before, i, j);
+                    let (r1, v1) = relative(&after, i, j);
+                    // Closing in at the start of the tick and separating at the end
+                    if r0.dot(v0) < 0.0 && r1.dot(v1) >= 0.0 {
+                        let closest = closest_approach(r0, v0, universe.physics.timestep).min(r1.length());
+                        if closest < *distance {
+                            record(vec![universe.bodies[i].id, universe.bodies[j].id], EventKind::CloseApproach { distance: closest, relative_speed: v1.length() });
+                        }
+                    }
+                }
+            },
+            Detector::Escape { radius } => {
+                let g = universe.physics.gravitational_constant;
+                let was_escaping = escaping(&universe.bodies, before, g, *radius);
+                for (i, now) in escaping(&universe.bodies, &after, g, *radius).into_iter().enumerate() {
+                    if let (Some((distance, specific_energy)), None) = (now, was_escaping[i]) {
+                        record(vec![universe.bodies[i].id], EventKind::Escape { distance, specific_energy });
+                    }
+                }
+            },
+            Detector::Apsides { primary } => {
+                for (i, p) in primaries(universe, *primary) {
+                    let (r0, v0) = relative(before, p, i);
+                    let (r1, v1) = relative(&after, p, i);
+                    let (s0, s1) = (r0.dot(v0), r1.dot(v1));
+                    let ids = vec![universe.bodies[i].id, universe.bodies[p].id];
+                    if s0 < 0.0 && s1 >= 0.0 {
+                        record(ids, EventKind::Periapsis { distance: r0.length().min(r1.length()) });
+                    } else if s0 > 0.0 && s1 <= 0.0 {
+                        record(ids, EventKind::Apoapsis { distance: r0.length().max(r1.length()) });
+                    }
+                }
+            },
+            Detector::Collision => {
+                for (i, j) in pairs(&universe.bodies) {
+                    let (Some(a), Some(b)) = (universe.bodies[i].radius, universe.bodies[j].radius) else {
+                        continue;
+                    };
+                    let contact = a + b;
+                    let (r0, v0) = relative(before, i, j);
+                    let (r1, v1) = relative(&after, i, j);
+                    // Passing right through each other within the tick counts too
+                    let passed_through = r0.dot(v0) < 0.0 && r1.dot(v1) >= 0.0 && closest_approach(r0, v0, universe.physics.timestep) < contact;
+                    if r0.length() >= contact && (r1.length() < contact || passed_through) {
+                        record(vec![universe.bodies[i].id, universe.bodies[j].id], EventKind::Collision { distance: r1.length(), relative_speed: v0.length().max(v1.length()) });
+                    }
+                }
+            },
+        }
+    }
+    found
+}
+
+// Every pair of bodies, other than pairs of test particles
+fn pairs(bodies: &[Body]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    (0..bodies.len()).flat_map(move |i| (i + 1..bodies.len()).map(move |j| (i, j)))
+        .filter(|&(i, j)| !(bodies[i].test_particle && bodies[j].test_particle))
+}
+
+// Position and velocity of j relative to i
+fn relative(state: &[(Pos, Pos)], i: usize, j: usize) -> (Pos, Pos) {
+    (state[j].0 - state[i].0, state[j].1 - state[i].1)
+}
+
+// Closest the separation r gets within dt when changing at v in a straight line
+fn closest_approach(r: Pos, v: Pos, dt: f64) -> f64 {
+    let speed_squared = v.dot(v);
+    let t = if speed_squared > 0.0 { (-r.dot(v) / speed_squared).clamp(0.0, dt) } else { 0.0 };
+    (r + v * t).length()
+}
+
+// For each body, its distance from the massive bodies' center of mass and specific energy if it's escaping
+fn escaping(bodies: &[Body], state: &[(Pos, Pos)], g: f64, radius: f64) -> Vec<Option<(f64, f64)>> {
+    let total: f64 = bodies.iter().map(|b| b.gravitating_mass()).sum();
+    if total <= 0.0 {
+        return vec![None; bodies.len()];
+    }
+    let weighted = |pick: fn(&(Pos, Pos)) -> Pos| -> Pos {
+        bodies.iter().zip(state).map(|(b, s)| pick(s) * (b.gravitating_mass() / total)).sum()
+    };
+    let (center, center_velocity) = (weighted(|s| s.0), weighted(|s| s.1));
+    (0..bodies.len()).map(|i| {
+        let distance = state[i].0.dist(center);
+        if distance <= radius {
+            return None;
+        }
+        let v = state[i].1 - center_velocity;
+        let potential: f64 = (0..bodies.len())
+            .filter(|&j| j != i && bodies[j].gravitating_mass() > 0.0)
+            .map(|j| g * bodies[j].gravitating_mass() / state[i].0.dist(state[j].0))
+            .sum();
+        let specific_energy = v.dot(v) / 2.0 - potential;
+        (specific_energy > 0.0).then_some((distance, specific_energy))
+    }).collect()
+}
+
+// (secondary, primary) index pairs to look for apsides in
+fn primaries(universe: &Universe, primary: Option<Uuid>) -> Vec<(usize, usize)> {
+    let index = |id: Uuid| universe.bodies.iter().position(|b| b.id == id);
+    match primary {
+        Some(primary) => match index(primary) {
+            Some(p) => (0..universe.bodies.len()).filter(|&i| i != p).map(|i| (i, p)).collect(),
+            None => vec![],
+        },
+        None => orbit::hierarchy(universe).into_iter()
+            .filter_map(|(body, primary)| Some((index(body)?, index(primary?)?)))
+            .collect(),
+    }
+}
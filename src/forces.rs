@@ -0,0 +1,324 @@
+// Forces on top of (or instead of) Newtonian point gravity. Each universe carries its own list, which its
+// children inherit and deltas can change, so branches can differ in their physics as well as their bodies.
+// Every force acts on free bodies and test particles alike; pinned and driven bodies ignore all of them.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::simulation::{Pos, Universe};
+
+// Metres per second, to go with the default SI gravitational constant
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+pub trait ForceModel {
+    // Adds the force's acceleration of each body to accelerations, which lines up with universe.bodies
+    fn accelerate(&self, universe: &Universe, accelerations: &mut [Pos]);
+
+    // Models that compute gravity themselves stand in for the built-in Newtonian pull
+    fn replaces_gravity(&self) -> bool {
+        false
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // The force's share of the potential energy of the bodies that aren't test particles. None for forces
+    // without one, such as drag, under which a universe's energy isn't conserved.
+    fn potential(&self, _universe: &Universe) -> Option<f64> {
+        None
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Force {
+    UniformField(UniformField),
+    Drag(Drag),
+    PostNewtonian(PostNewtonian),
+    RadiationPressure(RadiationPressure),
+    SoftenedGravity(SoftenedGravity),
+}
+
+impl Force {
+    pub fn model(&self) -> &dyn ForceModel {
+        match self {
+            Force::UniformField(f) => f,
+            Force::Drag(f) => f,
+            Force::PostNewtonian(f) => f,
+            Force::RadiationPressure(f) => f,
+            Force::SoftenedGravity(f) => f,
+        }
+    }
+}
+
+// The same acceleration everywhere, like gravity near a planet's surface
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct UniformField
+{
+    pub acceleration: Pos,
+}
+
+impl ForceModel for UniformField {
+    fn accelerate(&self, _universe: &Universe, accelerations: &mut [Pos]) {
+        for acceleration in accelerations {
+            *acceleration += self.acceleration;
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let a = self.acceleration;
+        if [a.x, a.y, a.z].iter().all(|c| c.is_finite()) { Ok(()) } else { Err(String::from("A uniform field needs a finite acceleration")) }
+    }
+
+    // -m * (a . x), like m * g * h near a planet's surface
+    fn potential(&self, universe: &Universe) -> Option<f64> {
+        Some(universe.bodies.iter()
+            .filter(|b| !b.test_particle)
+            .map(|b| -b.mass * self.acceleration.dot(b.position))
+            .sum())
+    }
+}
+
+// Slows bodies relative to a medium: -(linear + quadratic * |u|) * u, where u is the velocity through the medium
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Drag
+{
+    #[serde(default)]
+    pub linear: f64,
+    #[serde(default)]
+    pub quadratic: f64,
+    #[serde(default)]
+    pub medium_velocity: Pos,
+}
+
+impl ForceModel for Drag {
+    fn accelerate(&self, universe: &Universe, accelerations: &mut [Pos]) {
+        for (body, acceleration) in universe.bodies.iter().zip(accelerations) {
+            let u = body.velocity - self.medium_velocity;
+            *acceleration += u * -(self.linear + self.quadratic * u.length());
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.linear >= 0.0 && self.quadratic >= 0.0 && self.linear.is_finite() && self.quadratic.is_finite() {
+            Ok(())
+        } else {
+            Err(String::from("Drag coefficients must be finite and non-negative"))
+        }
+    }
+}
+
+// The first post-Newtonian correction from general relativity, which makes orbits close to a heavy body
+// precess the way Mercury's does. Each massive body contributes as if it were a fixed central mass.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PostNewtonian
+{
+    #[serde(default = "speed_of_light")]
+    pub speed_of_light: f64,
+}
+
+impl ForceModel for PostNewtonian {
+    fn accelerate(&self, universe: &Universe, accelerations: &mut [Pos]) {
+        let g = universe.physics.gravitational_constant;
+        let c2 = self.speed_of_light * self.speed_of_light;
+        for (i, (body, acceleration)) in universe.bodies.iter().zip(accelerations).enumerate() {
+            for (j, source) in universe.bodies.iter().enumerate() {
+                let mu = g * source.gravitating_mass();
+                let r = body.position - source.position;
+                let distance = r.length();
+                if i == j || mu == 0.0 || distance == 0.0 {
+                    continue;
+                }
+                let v = body.velocity - source.velocity;
+                let scale = mu / (c2 * distance.powi(3));
+                *acceleration += (r * (4.0 * mu / distance - v.dot(v)) + v * (4.0 * r.dot(v))) * scale;
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.speed_of_light.is_finite() && self.speed_of_light > 0.0 { Ok(()) } else { Err(String::from("The speed of light must be positive")) }
+    }
+}
+
+// Light from the source body pushing on every other body with a radius and a mass:
+// luminosity * efficiency * radius² / (4 * c * mass * r²), directed away from the source
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RadiationPressure
+{
+    pub source: Uuid,
+    pub luminosity: f64,
+    // Radiation pressure coefficient, 1 for a perfect absorber and 2 for a perfect mirror
+    #[serde(default = "one")]
+    pub efficiency: f64,
+    #[serde(default = "speed_of_light")]
+    pub speed_of_light: f64,
+}
+
+impl ForceModel for RadiationPressure {
+    fn accelerate(&self, universe: &Universe, accelerations: &mut [Pos]) {
+        let Some(source) = universe.get_body(self.source) else {
+            return;
+        };
+        for (body, acceleration) in universe.bodies.iter().zip(accelerations) {
+            let (Some(radius), r) = (body.radius, body.position - source.position) else {
+                continue;
+            };
+            let distance = r.length();
+            if body.id == self.source || body.mass <= 0.0 || distance == 0.0 {
+                continue;
+            }
+            let magnitude = self.luminosity * self.efficiency * radius * radius / (4.0 * self.speed_of_light * body.mass * distance * distance);
+            *acceleration += r * (magnitude / distance);
+        }
+    }
+
+    // The push falls off as 1/r², so its potential is + luminosity * efficiency * radius² / (4 * c * r). The source
+    // feels no push back, so this only accounts for the energy while the source stays where it is.
+    fn potential(&self, universe: &Universe) -> Option<f64> {
+        let Some(source) = universe.get_body(self.source) else {
+            return Some(0.0);
+        };
+        Some(universe.bodies.iter()
+            .filter(|b| !b.test_particle && b.id != self.source && b.mass > 0.0)
+            .filter_map(|b| Some((b.radius?, b.position.dist(source.position))))
+            .filter(|(_, distance)| *distance > 0.0)
+            .map(|(radius, distance)| self.luminosity * self.efficiency * radius * radius / (4.0 * self.speed_of_light * distance))
+            .sum())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(self.luminosity.is_finite() && self.luminosity >= 0.0 && self.efficiency.is_finite() && self.efficiency >= 0.0) {
+            return Err(String::from("Radiation pressure needs a finite, non-negative luminosity and efficiency"));
+        }
+        if self.speed_of_light.is_finite() && self.speed_of_light > 0.0 { Ok(()) } else { Err(String::from("The speed of light must be positive")) }
+    }
+}
+
+// Gravity with a softening length, g * m * r / (r² + softening²)^(3/2), which stays finite when bodies pass
+// through each other. Replaces the Newtonian pull rather than adding to it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SoftenedGravity
+{
+    pub softening: f64,
+}
+
+impl ForceModel for SoftenedGravity {
+    fn accelerate(&self, universe: &Universe, accelerations: &mut [Pos]) {
+        newtonian(universe, self.softening, accelerations);
+    }
+
+    fn replaces_gravity(&self) -> bool {
+        true
+    }
+
+    fn potential(&self, universe: &Universe) -> Option<f64> {
+        Some(newtonian_potential(universe, self.softening))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.softening.is_finite() && self.softening >= 0.0 { Ok(()) } else { Err(String::from("The softening length must be finite and non-negative")) }
+    }
+}
+
+fn speed_of_light() -> f64 {
+    SPEED_OF_LIGHT
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+// Point gravity between every body, optionally softened. Only massive bodies pull, so test particles cost
+// O(massive) each rather than O(everything). Deterministic universes add up each body's pulls in order of id
+// rather than the order the bodies were added in, and compensated ones add them up with Kahan summation.
+pub fn newtonian(universe: &Universe, softening: f64, accelerations: &mut [Pos]) {
+    let g = universe.physics.gravitational_constant;
+    let bodies = &universe.bodies;
+    let mut order: Vec<usize> = (0..bodies.len()).filter(|&i| !bodies[i].test_particle).collect();
+    if universe.physics.deterministic {
+        order.sort_by_key(|&i| bodies[i].id);
+    }
+    for (body, acceleration) in bodies.iter().zip(accelerations) {
+        let pulls = order.iter().map(|&i| body.get_softened_pull(&bodies[i], g, softening));
+        *acceleration += if universe.physics.compensated { Pos::sum_compensated(pulls) } else { pulls.sum() };
+    }
+}
+
+// Potential energy of the pull newtonian computes with the same softening, -g * m1 * m2 / (r² + softening²)^(1/2)
+// over each pair of massive bodies. Coincident bodies don't pull each other, so they add nothing.
+pub fn newtonian_potential(universe: &Universe, softening: f64) -> f64 {
+    let g = universe.physics.gravitational_constant;
+    let bodies: Vec<_> = universe.bodies.iter().filter(|b| !b.test_particle).collect();
+    let mut potential = 0.0;
+    for (i, a) in bodies.iter().enumerate() {
+        for b in &bodies[i + 1..] {
+            let r = a.position.dist_sq(b.position);
+            if r > 0.0 {
+                potential -= g * a.mass * b.mass / (r + softening * softening).sqrt();
+            }
+        }
+    }
+    potential
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::{integrator::Integrator, simulation::{Body, Physics}};
+
+    // Angle of each periapsis a test particle passes on an orbit with a = 1 and e = 0.5 around a unit mass,
+    // starting at periapsis on the +x axis
+    fn periapsis_angles(forces: Vec<Force>, orbits: usize) -> Vec<f64> {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, timestep: 5e-4, integrator: Integrator::Yoshida4, ..Default::default() });
+        universe.forces = forces;
+        universe.add_body(Body { mass: 1.0, ..Body::new() });
+        universe.add_body(Body { position: Pos { x: 0.5, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 3f64.sqrt(), z: 0.0 }, test_particle: true, ..Body::new() });
+
+        let mut angles = vec![];
+        // Starting at periapsis, so the start doesn't count as a passage
+        let (mut before, mut last) = (0.0, universe.bodies[1].position);
+        while angles.len() < orbits {
+            universe.tick();
+            let position = universe.bodies[1].position;
+            if last.length() < before && last.length() < position.length() {
+                angles.push(last.y.atan2(last.x));
+            }
+            (before, last) = (last.length(), position);
+        }
+        angles
+    }
+
+    #[test]
+    fn post_newtonian_orbits_precess_at_the_rate_general_relativity_predicts() {
+        let c = 20.0;
+        // 6 pi G M / (c² a (1 - e²)) per orbit
+        let expected = 6.0 * PI / (c * c * 0.75);
+        let precession = periapsis_angles(vec![Force::PostNewtonian(PostNewtonian { speed_of_light: c })], 3)[2] / 3.0;
+        assert!((precession - expected).abs() < 0.05 * expected, "{} vs {}", precession, expected);
+        // A Newtonian orbit stays put
+        let newtonian = periapsis_angles(vec![], 3)[2] / 3.0;
+        assert!(newtonian.abs() < 0.05 * expected, "{}", newtonian);
+    }
+
+    #[test]
+    fn radiation_pressure_pushes_away_from_the_source() {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 0.0, ..Default::default() });
+        let sun = Body { mass: 1.0, ..Body::new() };
+        let pressure = RadiationPressure { source: sun.id, luminosity: 8.0, efficiency: 1.0, speed_of_light: 1.0 };
+        universe.forces.push(Force::RadiationPressure(pressure));
+        universe.add_body(sun);
+        universe.add_body(Body { mass: 2.0, radius: Some(1.0), position: Pos { x: -2.0, y: 0.0, z: 0.0 }, ..Body::new() });
+        universe.add_body(Body { mass: 2.0, position: Pos { x: 2.0, y: 0.0, z: 0.0 }, ..Body::new() });
+
+        let accelerations = universe.accelerations();
+        assert!(accelerations[0].is_zero());
+        // 8 * 1 * 1² / (4 * 1 * 2 * 2²), pointing along -x like the sail's position
+        assert!((accelerations[1] - Pos { x: -0.25, y: 0.0, z: 0.0 }).length() < 1e-15);
+        // Nothing to catch the light without a radius
+        assert!(accelerations[2].is_zero());
+    }
+}
@@ -19,7 +19,7 @@ impl Handle {
         }
     }
 
-    pub fn new_from(id: &String) -> Handle{
+    pub fn new_from(id: &str) -> Handle{
         let mut h = Handle::new();
         h.id = Uuid::from_str(id).unwrap();
         h
@@ -27,6 +27,17 @@ impl Handle {
 
     // convience getter, doesn't both checking cache
     pub fn get<T>(&self, store: impl Store<T>) -> Option<T> {
-        store.get(&self)
+        store.get(self)
     }
-}
\ No newline at end of file
+}
+impl Default for Handle {
+    fn default() -> Self {
+        Handle::new()
+    }
+}
+
+impl From<Uuid> for Handle {
+    fn from(id: Uuid) -> Self {
+        Handle { id }
+    }
+}
@@ -0,0 +1,270 @@
+// How a universe moves through a tick. With no tolerance set, each tick is one fixed step of the physics'
+// integrator: the original kick-then-drift symplectic Euler by default, or a higher order symplectic one
+// built from leapfrog steps, which keep the energy error bounded over long runs instead of letting it drift.
+// Velocity-dependent forces such as drag see the velocities of the moment, and lose that guarantee.
+//
+// With a tolerance set, each tick still advances by exactly one timestep, so relative_age keeps meaning the same
+// simulated duration, but the tick is covered by as many Dormand-Prince 5(4) substeps as the error estimate asks
+// for: few in quiet stretches, many through close approaches.
+//
+// Only free bodies are integrated; pinned and driven ones are put where their motion says at every stage.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::{Pos, Universe};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum Integrator {
+    // First order, one force evaluation per tick
+    #[default]
+    SymplecticEuler,
+    // Drift, kick, drift. Second order, one force evaluation per tick.
+    Leapfrog,
+    // Yoshida's compositions of leapfrog steps: fourth order with three force evaluations per tick, sixth with seven
+    Yoshida4,
+    Yoshida6,
+}
+
+impl Integrator {
+    pub fn is_default(&self) -> bool {
+        *self == Integrator::default()
+    }
+
+    // Fractions of the timestep each leapfrog step in the composition takes
+    fn weights(&self) -> Vec<f64> {
+        match self {
+            Integrator::SymplecticEuler | Integrator::Leapfrog => vec![1.0],
+            Integrator::Yoshida4 => {
+                let w1 = 1.0 / (2.0 - 2f64.cbrt());
+                vec![w1, 1.0 - 2.0 * w1, w1]
+            },
+            // Yoshida's solution A
+            Integrator::Yoshida6 => {
+                let (w1, w2, w3) = (-1.17767998417887, 0.235573213359357, 0.784513610477560);
+                vec![w3, w2, w1, 1.0 - 2.0 * (w1 + w2 + w3), w1, w2, w3]
+            },
+        }
+    }
+}
+
+// Smallest substep, as a fraction of the timestep. Substeps this small are taken whatever their error,
+// so bodies passing through each other can't stall the integrator.
+pub const MIN_STEP_FRACTION: f64 = 1e-9;
+
+const C: [f64; 7] = [0.0, 1.0 / 5.0, 3.0 / 10.0, 4.0 / 5.0, 8.0 / 9.0, 1.0, 1.0];
+const A: [[f64; 6]; 7] = [
+    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [1.0 / 5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [3.0 / 40.0, 9.0 / 40.0, 0.0, 0.0, 0.0, 0.0],
+    [44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0, 0.0, 0.0, 0.0],
+    [19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0, 0.0, 0.0],
+    [9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0, 0.0],
+    [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0],
+];
+// Fifth order weights, and the fourth order ones the error is measured against
+const B: [f64; 7] = [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0, 0.0];
+const B_LOW: [f64; 7] = [5179.0 / 57600.0, 0.0, 7571.0 / 16695.0, 393.0 / 640.0, -92097.0 / 339200.0, 187.0 / 2100.0, 1.0 / 40.0];
+
+// Positions and velocities of every body
+struct State {
+    positions: Vec<Pos>,
+    velocities: Vec<Pos>,
+}
+
+impl State {
+    fn of(universe: &Universe) -> State {
+        State {
+            positions: universe.bodies.iter().map(|b| b.position).collect(),
+            velocities: universe.bodies.iter().map(|b| b.velocity).collect(),
+        }
+    }
+
+    // Loads the state into the universe at time, returning the accelerations there
+    fn load(&self, universe: &mut Universe, time: f64) -> Vec<Pos> {
+        for (body, (position, velocity)) in universe.bodies.iter_mut().zip(self.positions.iter().zip(&self.velocities)) {
+            body.position = *position;
+            body.velocity = *velocity;
+        }
+        universe.time = time;
+        universe.follow_motion();
+        universe.accelerations()
+    }
+}
+
+// start plus h times the weighted sum of the stage derivatives, for free bodies only
+fn combine(universe: &Universe, start: &State, stages: &[(Vec<Pos>, Vec<Pos>)], weights: &[f64], h: f64) -> State {
+    let mut state = State { positions: start.positions.clone(), velocities: start.velocities.clone() };
+    for (i, body) in universe.bodies.iter().enumerate() {
+        if !body.motion.is_free() {
+            continue;
+        }
+        for ((dx, dv), weight) in stages.iter().zip(weights) {
+            if *weight != 0.0 {
+                state.positions[i] += dx[i] * (h * weight);
+                state.velocities[i] += dv[i] * (h * weight);
+            }
+        }
+    }
+    state
+}
+
+// One Dormand-Prince step of h from start, given the accelerations there. Returns the new state, its error
+// relative to the tolerance, where anything up to 1 is acceptable, and the accelerations at the new state.
+// The last stage is evaluated at the new state, so the universe is left there and the accelerations come free.
+fn step(universe: &mut Universe, start: &State, first: &[Pos], time: f64, h: f64, tolerance: f64) -> (State, f64, Vec<Pos>) {
+    // (d position, d velocity) at each stage
+    let mut stages: Vec<(Vec<Pos>, Vec<Pos>)> = vec![(start.velocities.clone(), first.to_vec())];
+    for s in 1..7 {
+        let state = combine(universe, start, &stages, &A[s][..s], h);
+        let accelerations = state.load(universe, time + C[s] * h);
+        // Pinned and driven bodies were moved by load, so their velocities are read back from the universe
+        let velocities = universe.bodies.iter().map(|b| b.velocity).collect();
+        stages.push((velocities, accelerations));
+    }
+    let end = combine(universe, start, &stages, &B, h);
+    let weights: Vec<f64> = B.iter().zip(B_LOW).map(|(high, low)| high - low).collect();
+    let error = combine(universe, &State { positions: vec![Pos::default(); start.positions.len()], velocities: vec![Pos::default(); start.velocities.len()] }, &stages, &weights, h);
+
+    let mut sum = 0.0;
+    let mut count = 0;
+    for (i, body) in universe.bodies.iter().enumerate() {
+        if !body.motion.is_free() {
+            continue;
+        }
+        let pairs = [(start.positions[i], end.positions[i], error.positions[i]), (start.velocities[i], end.velocities[i], error.velocities[i])];
+        for (before, after, e) in pairs {
+            for (b, a, e) in [(before.x, after.x, e.x), (before.y, after.y, e.y), (before.z, after.z, e.z)] {
+                let scale = tolerance * (1.0 + b.abs().max(a.abs()));
+                sum += (e / scale).powi(2);
+                count += 1;
+            }
+        }
+    }
+    let norm = if count > 0 { (sum / count as f64).sqrt() } else { 0.0 };
+    let (_, last) = stages.pop().expect("Dormand-Prince has seven stages");
+    (end, norm, last)
+}
+
+// Advances the universe by one fixed step of its integrator
+pub fn fixed_tick(universe: &mut Universe) {
+    let dt = universe.physics.timestep;
+    let integrator = universe.physics.integrator;
+    if integrator == Integrator::SymplecticEuler {
+        kick(universe, dt);
+        drift(universe, dt);
+    } else {
+        let start_time = universe.time;
+        // Neighbouring leapfrog steps' half drifts are taken as one
+        let mut previous = 0.0;
+        for weight in integrator.weights() {
+            drift(universe, (previous + weight) / 2.0 * dt);
+            kick(universe, weight * dt);
+            previous = weight;
+        }
+        drift(universe, previous / 2.0 * dt);
+        // The drifts' times needn't add up to exactly one timestep
+        universe.time = start_time + dt;
+        universe.follow_motion();
+    }
+    universe.substeps += 1;
+}
+
+// Changes free bodies' velocities by h's worth of their accelerations
+fn kick(universe: &mut Universe, h: f64) {
+    let compensated = universe.physics.compensated;
+    let accelerations = universe.accelerations();
+    for (body, acceleration) in universe.bodies.iter_mut().zip(accelerations) {
+        if !body.motion.is_free() {
+            continue;
+        }
+        if compensated {
+            body.velocity.add_compensated(acceleration * h, &mut body.compensation.velocity);
+        } else {
+            body.velocity += acceleration * h;
+        }
+    }
+}
+
+// Moves free bodies along their velocities for h, and the clock and everything else with them
+fn drift(universe: &mut Universe, h: f64) {
+    let compensated = universe.physics.compensated;
+    universe.time += h;
+    for body in &mut universe.bodies {
+        if !body.motion.is_free() {
+            continue;
+        }
+        if compensated {
+            body.position.add_compensated(body.velocity * h, &mut body.compensation.position);
+        } else {
+            body.tick(h);
+        }
+    }
+    universe.follow_motion();
+}
+
+// Advances the universe by one timestep in adaptive substeps, starting from the step size it was left with
+pub fn adaptive_tick(universe: &mut Universe, tolerance: f64) {
+    let dt = universe.physics.timestep;
+    let start_time = universe.time;
+    let min_step = dt * MIN_STEP_FRACTION;
+    let mut h = universe.step.unwrap_or(dt).min(dt);
+    let mut done = 0.0;
+    let mut state = State::of(universe);
+    let mut first = state.load(universe, start_time);
+    while done < dt {
+        let clipped = h >= dt - done;
+        let size = if clipped { dt - done } else { h };
+        let (end, error, last) = step(universe, &state, &first, start_time + done, size, tolerance);
+        // The usual safety factor, with the change in step size kept within a factor of five either way
+        let factor = if error == 0.0 { 5.0 } else { (0.9 * error.powf(-0.2)).clamp(0.2, 5.0) };
+        if error <= 1.0 || size <= min_step {
+            done = if clipped { dt } else { done + size };
+            state = end;
+            first = last;
+            universe.substeps += 1;
+            // A step cut short to land on the end of the tick says little about the next one
+            if !clipped || factor < 1.0 {
+                h = (size * factor).max(min_step);
+            }
+        } else {
+            h = (size * factor).max(min_step);
+        }
+    }
+    // The last accepted step left the bodies at the end of the tick. The clock goes exactly where a fixed step
+    // would have put it.
+    universe.time = start_time + dt;
+    universe.step = Some(h);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{Body, Physics};
+
+    // Largest relative energy error over a few orbits of an eccentric pair
+    fn energy_error(integrator: Integrator) -> f64 {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, timestep: 0.01, integrator, ..Default::default() });
+        universe.add_body(Body { mass: 1.0, ..Body::new() });
+        universe.add_body(Body { position: Pos { x: 0.5, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 3f64.sqrt(), z: 0.0 }, test_particle: true, ..Body::new() });
+        // Specific orbital energy of the particle around the unit mass
+        let energy = |universe: &Universe| {
+            let particle = &universe.bodies[1];
+            0.5 * particle.velocity.dot(particle.velocity) - 1.0 / particle.position.length()
+        };
+        let start = energy(&universe);
+        (0..2000).map(|_| {
+            universe.tick();
+            ((energy(&universe) - start) / start).abs()
+        }).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn yoshida4_holds_energy_far_better_than_leapfrog() {
+        let leapfrog = energy_error(Integrator::Leapfrog);
+        let yoshida4 = energy_error(Integrator::Yoshida4);
+        assert!(yoshida4 < leapfrog / 50.0, "{} vs {}", yoshida4, leapfrog);
+        assert!(energy_error(Integrator::Yoshida6) < yoshida4);
+    }
+}
@@ -0,0 +1,164 @@
+// The original GET-only routes, kept for clients that haven't moved to /api/v1 yet.
+// These are only mounted when legacy routes are enabled, since several of them mutate the multiverse.
+// They only ever see the default multiverse.
+// Malformed UUIDs fail the path extractor, which answers 404 just like the v1 routes.
+use actix_web::{get, web, HttpResponse, Responder};
+use uuid::Uuid;
+
+use crate::{api::{default_multiverse, node_not_found, request, unavailable}, delta::Delta, handle::Handle, multiverse::{AdvanceError, BranchParams}, multiverse_manager::MultiverseCommand, simulation::Pos, timeline::Timeline};
+
+#[get("/advance/{uuid}/{amount}")]
+async fn advance_node(path: web::Path<(Uuid, i32,)>) -> impl Responder {
+    let (id, amount) = path.into_inner();
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    match request(&chan, |tx| MultiverseCommand::AdvanceNode((Handle::from(id), amount, tx))).await {
+        Some(Ok(_)) => HttpResponse::Ok().body("Node advanced"),
+        Some(Err(AdvanceError::NotFound)) => node_not_found(),
+        Some(Err(e)) => HttpResponse::Conflict().body(e.to_string()),
+        None => unavailable(),
+    }
+}
+
+#[get("/branch/{uuid}")]
+async fn branch_node(path: web::Path<(Uuid,)>) -> impl Responder{
+    let params = vec![
+        BranchParams{
+            mass: Some(0.3),
+            position: Some(Pos{x: 0.0, y: 1.0, z: -0.5}),
+            ..Default::default()
+        },
+        BranchParams{
+            mass: Some(0.6),
+            position: Some(Pos{x: -1.0, y: 0.0, z: 0.0}),
+            velocity: Some(Pos{x: 0.1, y: 0.1, z: 0.1}),
+            ..Default::default()
+        },
+    ];
+    let target_handle = Handle::from(path.into_inner().0);
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    let deltas = params.into_iter().map(Delta::from).collect();
+    match request(&chan, |tx| MultiverseCommand::Branch((target_handle, deltas, 10, tx))).await {
+        Some(Some(_)) => HttpResponse::Ok().finish(),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/nodes")]
+async fn fetch_nodes() -> impl Responder {
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    match request(&chan, MultiverseCommand::GetNodes).await {
+        Some(nodes) => pretty(&nodes),
+        None => unavailable(),
+    }
+}
+
+#[get("/node/{uuid}")]
+async fn fetch_node(path: web::Path<(Uuid,)>) -> impl Responder {
+    let handle = Handle::from(path.into_inner().0);
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    match request(&chan, |tx| MultiverseCommand::GetNode((handle, tx))).await {
+        Some(Some(node)) => pretty(&node),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/universe/{uuid}")]
+async fn fetch_universe(path : web::Path<(Uuid,)>) -> impl Responder {
+    let handle = Handle::from(path.into_inner().0);
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    match request(&chan, |tx| MultiverseCommand::GetUniverse((handle, tx))).await {
+        Some(Some(u)) => pretty(&u),
+        Some(None) => node_not_found(),
+        None => unavailable(),
+    }
+}
+
+#[get("/timeline/{uuid}")]
+async fn fetch_timeline(path: web::Path<(Uuid,)>) -> impl Responder {
+    let handle = Handle::from(path.into_inner().0);
+    let Some(chan) = default_multiverse() else {
+        return unavailable();
+    };
+    match request(&chan, |tx| MultiverseCommand::GetTimneline((handle, tx))).await {
+        // Unknown nodes have always had an empty timeline here
+        Some(timeline) => pretty(&timeline.unwrap_or(Timeline { universes: vec![] })),
+        None => unavailable(),
+    }
+}
+
+// These routes have always answered with the pretty-printed JSON as a JSON string
+fn pretty(value: &impl serde::Serialize) -> HttpResponse {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => HttpResponse::Ok().json(json),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/api")
+        .service(fetch_nodes)
+        .service(fetch_universe)
+        .service(fetch_timeline)
+        .service(fetch_node)
+        .service(branch_node)
+        .service(advance_node));
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::api::{self, tests::{registry, root}};
+
+    #[actix_web::test]
+    async fn legacy_routes_are_absent_unless_enabled() {
+        registry();
+        let app = test::init_service(App::new().configure(api::configure)).await;
+        for path in ["/api/nodes".to_string(), format!("/api/node/{}", root()), format!("/api/branch/{}", root()), format!("/api/advance/{}/1", root())] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(&path).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        let app = test::init_service(App::new().configure(api::configure).configure(configure)).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/nodes").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/api/node/{}", root())).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn bad_uuids_are_not_found() {
+        registry();
+        let app = test::init_service(App::new().configure(configure)).await;
+        let missing = Uuid::new_v4();
+        for path in [
+            String::from("/api/node/not-a-uuid"),
+            String::from("/api/universe/not-a-uuid"),
+            String::from("/api/advance/not-a-uuid/1"),
+            format!("/api/node/{}", missing),
+            format!("/api/universe/{}", missing),
+            format!("/api/branch/{}", missing),
+            format!("/api/advance/{}/1", missing),
+        ] {
+            let resp = test::call_service(&app, test::TestRequest::get().uri(&path).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        // Unknown nodes have always had an empty timeline here
+        let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/api/timeline/{}", missing)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
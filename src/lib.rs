@@ -0,0 +1,24 @@
+pub mod simulation;
+pub mod timeline;
+pub mod store;
+pub mod handle;
+pub mod multiverse;
+pub mod multiverse_manager;
+pub mod api;
+pub mod legacy_api;
+pub mod openapi;
+pub mod config;
+pub mod diagnostics;
+pub mod orbit;
+pub mod scenario;
+pub mod registry;
+pub mod diff;
+pub mod divergence;
+pub mod sweep;
+pub mod ensemble;
+pub mod delta;
+pub mod determinism;
+pub mod motion;
+pub mod forces;
+pub mod integrator;
+pub mod events;
@@ -1,19 +1,9 @@
-pub mod simulation;
-pub mod timeline;
-pub mod store;
-pub mod handle;
-pub mod multiverse;
-pub mod multiverse_manager;
+use std::process;
 
-use std::{sync::{mpsc::{self, Sender}, OnceLock}, thread};
-
-use actix_web::{get, middleware, web, App, HttpResponse, HttpServer, Responder};
-use handle::Handle;
-use multiverse::BranchParams;
-use multiverse_manager::MultiverseCommand;
-use schemars::{schema_for, JsonSchema};
-use serde::{Deserialize, Serialize};
-use simulation::Pos;
+use actix_web::{get, middleware, App, HttpResponse, HttpServer, Responder};
+use clap::Parser;
+use multiverse_simulator::{api::{self, BranchArgs, REGISTRY}, config::{Cli, Config}, legacy_api, openapi, registry::Registry};
+use schemars::schema_for;
 
 #[get("/")]
 async fn hello() -> impl Responder {
@@ -21,54 +11,9 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
-#[get("/advance/{uuid}/{amount}")]
-async fn advance_node(path: web::Path<(String, i32,)>) -> impl Responder {
-    let vals = path.into_inner();
-    let handle = Handle::new_from(&vals.0);
-    CHAN.get().unwrap().send(MultiverseCommand::AdvanceNode((handle, vals.1)));
-    HttpResponse::Ok().body("Node advanced")
-}
-
-#[derive(Serialize, Deserialize, JsonSchema)]
-pub struct BranchArgs{
-    deltas: Vec<BranchParams>,
-    duration: i32
-}
-
-#[get("/branch/{uuid}")]
-async fn branch_node(path: web::Path<(String,)>/*, json: web::Json<BranchArgs>*/) -> impl Responder{
-    let mut params = vec![];
-    let mut p1 = BranchParams::default();
-    p1.mass = Some(0.3);
-    p1.position = Some(Pos{x: 0.0, y: 1.0, z: -0.5});
-    params.push(p1);
-    let mut p2 = BranchParams::default();
-    p2.mass = Some(0.6);
-    p2.position = Some(Pos{x: -1.0, y: 0.0, z: 0.0});
-    p2.velocity = Some(Pos{x: 0.1, y: 0.1, z: 0.1});
-    params.push(p2);
-    let target_handle = Handle::new_from(&path.into_inner().0.to_string());
-    CHAN.get().unwrap().send(MultiverseCommand::Branch((target_handle, params, 10)));
-    HttpResponse::Ok()
-}
-
-#[get("/nodes")]
-async fn fetch_nodes() -> impl Responder {
-    let (tx, rx) = mpsc::channel();
-    CHAN.get().unwrap().send(MultiverseCommand::GetNodes(tx));
-    let nodes = rx.recv().expect("Failed to fetch nodes");
-    let json = serde_json::to_string_pretty(&nodes);
-    HttpResponse::Ok().json(json.unwrap())
-}
-
-#[get("/node/{uuid}")]
-async fn fetch_node(path: web::Path<(String,)>) -> impl Responder {
-    let (tx, rx) = mpsc::channel();
-    CHAN.get().unwrap().send(MultiverseCommand::GetNode((Handle::new_from(&path.into_inner().0), tx)));
-    match rx.recv() {
-        Ok(opt) => HttpResponse::Ok().json(serde_json::to_string_pretty(&opt.unwrap()).unwrap()),
-        Err(_) => HttpResponse::NotFound().body("Failed to find uuid"),
-    }
+#[get("/api/openapi.json")]
+async fn openapi_document() -> impl Responder {
+    HttpResponse::Ok().json(openapi::document())
 }
 
 #[get("/schema")]
@@ -77,52 +22,62 @@ async fn schema() -> impl Responder {
     HttpResponse::Ok().body(serde_json::to_string_pretty(&schema).unwrap())
 }
 
-#[get("/universe/{uuid}")]
-async fn fetch_universe(path : web::Path<(String,)>) -> impl Responder {
-    let (tx, rx) = mpsc::channel();
-    let handle = Handle::new_from(&path.into_inner().0);
-    CHAN.get().unwrap().send(MultiverseCommand::GetUniverse((handle, tx)));
-    match rx.recv().expect("Failed to read universe") {
-        Some(u) => HttpResponse::Ok().json(serde_json::to_string_pretty(&u).unwrap()),
-        None => HttpResponse::Ok().body("Universe not found. Please double check the submitted UUID")
-    }
-}
-
-#[get("/timeline/{uuid}")]
-async fn fetch_timeline(path: web::Path<(String,)>) -> impl Responder {
-    let (tx, rx) = mpsc::channel();
-    let handle = Handle::new_from(&path.into_inner().0);
-    CHAN.get().unwrap().send(MultiverseCommand::GetTimneline((handle, tx)));
-    let timeline = rx.recv().unwrap();
-    HttpResponse::Ok().json(serde_json::to_string_pretty(&timeline).unwrap())
-}
-
-
-static CHAN: OnceLock<Sender<MultiverseCommand>> = OnceLock::new();
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    println!("Starting multiverse...");
-    let (tx, rx) = mpsc::channel();
-    let multiverse_thread = thread::spawn(move || {
-        multiverse_manager::start_multiverse(rx);
-    });
-    CHAN.set(tx);
-    println!("Starting webserver...");
-    HttpServer::new(|| {
-        let api_scope = web::scope("/api")
-            .service(fetch_nodes)
-            .service(fetch_universe)
-            .service(fetch_timeline)
-            .service(fetch_node)
-            .service(branch_node)
-            .service(advance_node);
-        App::new()
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+    env_logger::Builder::new().parse_filters(&config.log_level).init();
+
+    println!("Starting multiverses...");
+    if REGISTRY.set(Registry::open(config.clone())).is_err() {
+        panic!("Multiverse registry already initialized");
+    }
+    let legacy_routes = config.legacy_routes;
+    if legacy_routes {
+        println!("Serving legacy GET routes under /api");
+    }
+    println!("Starting webserver on {}:{}...", config.bind_address, config.port);
+    let server = HttpServer::new(move || {
+        // Registered before the legacy /api scope, which would otherwise swallow it
+        let app = App::new()
             .wrap(middleware::Logger::default())
-            .service(api_scope)
-            .service(schema)
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
-}
\ No newline at end of file
+            .service(openapi_document)
+            .configure(api::configure);
+        let app = if legacy_routes {
+            app.configure(legacy_api::configure)
+        } else {
+            app
+        };
+        app.service(schema)
+    });
+    let server = match config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    // actix stops on SIGINT/SIGTERM once in-flight requests finish, after which nothing else can be queued
+    let result = server
+        .bind((config.bind_address.as_str(), config.port))?
+        .run()
+        .await;
+
+    println!("Shutting down multiverses...");
+    if let Some(registry) = REGISTRY.get() {
+        for (id, stopped) in registry.shutdown() {
+            match stopped {
+                Ok(summary) => println!("Multiverse {} stopped: {}", id, summary),
+                Err(e) => eprintln!("Multiverse {}: {}", id, e),
+            }
+        }
+    }
+    result
+}
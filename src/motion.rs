@@ -0,0 +1,140 @@
+// How a body moves. Free bodies follow the pulls on them; pinned and driven ones ignore those pulls
+// but still pull on everything else, which is what restricted problems and scripted probes need.
+use std::f64::consts::PI;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::Pos;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Motion {
+    #[default]
+    Free,
+    // Infinite inertia: stays where it is, at rest
+    Pinned,
+    // Follows the trajectory, placed by the universe's time
+    Driven { trajectory: Trajectory },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "path", rename_all = "snake_case")]
+pub enum Trajectory {
+    // At start when the universe's time is start_time, moving in a straight line
+    Linear {
+        start: Pos,
+        velocity: Pos,
+        #[serde(default)]
+        start_time: f64,
+    },
+    // Anticlockwise around center in the xy plane, at angle phase (radians from +x) when the time is zero
+    Circular {
+        center: Pos,
+        radius: f64,
+        period: f64,
+        #[serde(default)]
+        phase: f64,
+    },
+    // Straight between waypoints, holding still before the first and after the last
+    Tabulated { waypoints: Vec<Waypoint> },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Waypoint
+{
+    pub time: f64,
+    pub position: Pos,
+}
+
+impl Motion {
+    pub fn is_free(&self) -> bool {
+        matches!(self, Motion::Free)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Motion::Driven { trajectory } => trajectory.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Trajectory {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Trajectory::Linear { start_time, .. } if !start_time.is_finite() => Err(String::from("A linear path needs a finite start_time")),
+            Trajectory::Circular { radius, period, .. } if !(radius.is_finite() && *radius >= 0.0 && period.is_finite() && *period > 0.0) => {
+                Err(String::from("A circular path needs a non-negative radius and a positive period"))
+            },
+            Trajectory::Tabulated { waypoints } if waypoints.is_empty() => Err(String::from("A tabulated path needs at least one waypoint")),
+            Trajectory::Tabulated { waypoints } if waypoints.iter().any(|w| !w.time.is_finite()) || waypoints.windows(2).any(|w| w[1].time <= w[0].time) => {
+                Err(String::from("Waypoint times must be finite and strictly increasing"))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Where the path is, and how fast it's moving, at time
+    pub fn state(&self, time: f64) -> (Pos, Pos) {
+        match self {
+            Trajectory::Linear { start, velocity, start_time } => (*start + *velocity * (time - start_time), *velocity),
+            Trajectory::Circular { center, radius, period, phase } => {
+                let omega = 2.0 * PI / period;
+                let angle = phase + omega * time;
+                let (sin, cos) = angle.sin_cos();
+                (
+                    *center + Pos{ x: radius * cos, y: radius * sin, z: 0.0 },
+                    Pos{ x: -radius * omega * sin, y: radius * omega * cos, z: 0.0 },
+                )
+            },
+            Trajectory::Tabulated { waypoints } => {
+                let next = waypoints.partition_point(|w| w.time <= time);
+                match (next.checked_sub(1).map(|i| waypoints[i]), waypoints.get(next)) {
+                    (Some(a), Some(b)) => {
+                        let velocity = (b.position - a.position) * (1.0 / (b.time - a.time));
+                        (a.position + velocity * (time - a.time), velocity)
+                    },
+                    (Some(last), None) => (last.position, Pos::default()),
+                    (None, Some(first)) => (first.position, Pos::default()),
+                    (None, None) => (Pos::default(), Pos::default()),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{integrator::Integrator, simulation::{Body, Physics, Universe}};
+
+    #[test]
+    fn pinned_and_driven_bodies_follow_their_motion_exactly() {
+        let circle = Trajectory::Circular { center: Pos { x: 0.0, y: 0.0, z: 0.0 }, radius: 3.0, period: 5.0, phase: 0.5 };
+        let physics = [
+            Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() },
+            Physics { gravitational_constant: 1.0, timestep: 0.01, integrator: Integrator::Yoshida4, ..Default::default() },
+            Physics { gravitational_constant: 1.0, timestep: 0.01, tolerance: Some(1e-9), ..Default::default() },
+        ];
+        for physics in physics {
+            let mut universe = Universe::with_physics(physics);
+            let pinned_at = Pos { x: 0.5, y: -0.5, z: 0.0 };
+            universe.add_body(Body { mass: 10.0, position: pinned_at, motion: Motion::Pinned, ..Body::new() });
+            universe.add_body(Body { mass: 1.0, motion: Motion::Driven { trajectory: circle.clone() }, ..Body::new() });
+            universe.add_body(Body { mass: 1e-3, position: Pos { x: 1.5, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 2.0, z: 0.0 }, ..Body::new() });
+            universe.follow_motion();
+            let free_start = universe.bodies[2].position;
+
+            for _ in 0..20 {
+                universe.tick_for(10);
+                let (position, velocity) = circle.state(universe.time);
+                assert!((universe.bodies[0].position - pinned_at).is_zero());
+                assert!(universe.bodies[0].velocity.is_zero());
+                assert!(universe.bodies[1].position.dist(position) < 1e-12);
+                assert!(universe.bodies[1].velocity.dist(velocity) < 1e-12);
+            }
+            assert!(universe.bodies[2].position.dist(free_start) > 0.1);
+        }
+    }
+}
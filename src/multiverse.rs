@@ -1,9 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::{BTreeMap, HashMap}, fmt, fs, path::Path};
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::{handle::Handle, simulation::{Body, Pos, Universe}, store::{Store, StoreSQL}, timeline::Timeline};
+use crate::{delta::{self, Delta}, determinism::{self, UniverseHash, HASH_STORE_FILE}, ensemble::{Ensemble, EnsembleCreated, EnsembleError, EnsembleTag}, handle::Handle, motion::Motion, orbit::{self, OrbitSpec}, scenario::Scenario, simulation::{Body, Physics, Pos, Universe, PHYSICS_VERSION}, store::{Store, StoreSQL}, sweep::{SweepTag, Variant}, timeline::Timeline};
+
+pub const NODE_STORE_FILE: &str = "multiverse_nodes.sqlite";
+pub const UNIVERSE_STORE_FILE: &str = "universe_store.sqlite";
+// Holds the PHYSICS_VERSION the cached universes were computed under
+pub const PHYSICS_VERSION_FILE: &str = "physics_version";
 
 pub struct Multiverse
 {
@@ -11,74 +17,131 @@ pub struct Multiverse
     pub nodes: HashMap<Handle, MultiverseNode>,
     pub node_store: Box<dyn Store<MultiverseNode>>,
     pub universe_store: Box<dyn Store<Universe>>,
+    // Content hashes of computed universes, keyed like universe_store but kept when a universe is evicted
+    pub hash_store: Box<dyn Store<UniverseHash>>,
+    // Physics given to root universes that are calculated from scratch. Its deterministic flag also decides
+    // how ids are picked for new nodes.
+    pub physics: Physics,
 }
 
 impl Multiverse {
 
     pub fn new() -> Multiverse {
-        let ns = Box::new(StoreSQL::new(String::from("./multiverse_nodes.sqlite")));
-        let us = Box::new(StoreSQL::new(String::from("./universe_store.sqlite")));
+        Multiverse::open_seeded(Path::new("."), Physics::default(), &Scenario::default())
+    }
+
+    // Opens (or creates) the multiverse stored in data_dir. A new multiverse has no root until it's seeded.
+    pub fn open(data_dir: &Path, physics: Physics) -> Multiverse {
+        fs::create_dir_all(data_dir).expect("Failed to create data directory");
+        let ns = Box::new(StoreSQL::new(data_dir.join(NODE_STORE_FILE).to_string_lossy().into_owned()));
+        let us = Box::new(StoreSQL::new(data_dir.join(UNIVERSE_STORE_FILE).to_string_lossy().into_owned()));
+        let hs = Box::new(StoreSQL::new(data_dir.join(HASH_STORE_FILE).to_string_lossy().into_owned()));
         let mut m = Multiverse{
             root_node: None,
             nodes: HashMap::new(),
             node_store: ns,
             universe_store: us,
+            hash_store: hs,
+            physics,
         };
-        println!("Loading nodes from storage");
+        log::info!("Loading nodes from storage");
+        // Handles come back in the order they were created, so the first root is the original one
         for h in m.node_store.get_handles() {
-            println!("Loading a node");
-            match m.node_store.get(&h) {
-                Some(node) => {
-                    if node.parent.is_none() {
-                        m.root_node = Some(h);
-                    }
-                    m.nodes.insert(h, node);
-                },
-                None => (),
+            log::debug!("Loading node {}", h.id);
+            if let Some(node) = m.node_store.get(&h) {
+                if node.parent.is_none() && m.root_node.is_none() {
+                    m.root_node = Some(h);
+                }
+                m.nodes.insert(h, node);
+            }
+        }
+        log::info!("Loaded {} nodes, root node: {:?}", m.nodes.len(), &m.root_node);
+        m.check_physics_version(data_dir);
+        m
+    }
+
+    // Universes cached under another version of the physics, or before versions were recorded, would
+    // disagree with a recompute, so they and their hashes are dropped and recomputed when next needed
+    fn check_physics_version(&self, data_dir: &Path) {
+        let path = data_dir.join(PHYSICS_VERSION_FILE);
+        let stored = fs::read_to_string(&path).ok().and_then(|v| v.trim().parse::<u32>().ok());
+        if stored == Some(PHYSICS_VERSION) {
+            return;
+        }
+        if self.universe_store.count() > 0 || self.hash_store.count() > 0 {
+            log::warn!("Cached universes were computed under physics version {:?}, not {}, dropping them", stored, PHYSICS_VERSION);
+            for handle in self.universe_store.get_handles() {
+                self.universe_store.delete_handle(handle);
+            }
+            for handle in self.hash_store.get_handles() {
+                self.hash_store.delete_handle(handle);
             }
         }
-        println!("Root node: {:?}", &m.root_node);
+        fs::write(&path, PHYSICS_VERSION.to_string()).expect("Failed to write physics version");
+    }
+
+    // Opens the multiverse in data_dir, giving it a root built from scenario if it doesn't have one yet
+    pub fn open_seeded(data_dir: &Path, physics: Physics, scenario: &Scenario) -> Multiverse {
+        let mut m = Multiverse::open(data_dir, physics);
         if m.root_node.is_none() {
-            let new_node = MultiverseNode::new(None, 0, vec![
-                BranchParams{
-                    target_body: Handle::new().id,
-                    position: Some(Pos{ x: 1.0, y: 2.0, z: 0.0 }),
-                    d_position: None,
-                    velocity: Some(Pos{x: 0.0, y: 0.0, z: 0.1}),
-                    d_velocity: None,
-                    mass: Some(1.0),
-                    d_mas: None }]);
-            let new_handle = m.node_store.save(new_node);
-            m.root_node = Some(new_handle);
-            println!("New root node: {:?}", &m.root_node);
+            m.create_root(scenario);
         }
         m
     }
 
+    // Adds a new parentless node built from scenario. The first root created becomes the multiverse's root_node.
+    pub fn create_root(&mut self, scenario: &Scenario) -> Handle {
+        let new_node = MultiverseNode::new(None, 0, scenario.deltas(self.physics.gravitational_constant));
+        let new_handle = self.insert_node(new_node);
+        if self.root_node.is_none() {
+            self.root_node = Some(new_handle);
+            log::info!("New root node: {:?}", &self.root_node);
+        }
+        new_handle
+    }
+
+    // Every parentless node, starting with root_node
+    pub fn roots(&self) -> Vec<Handle> {
+        let mut others: Vec<Handle> = self.nodes.iter()
+            .filter(|(h, n)| n.parent.is_none() && Some(**h) != self.root_node)
+            .map(|(h, _)| *h)
+            .collect();
+        others.sort_by_key(|h| h.id);
+        self.root_node.into_iter().chain(others).collect()
+    }
+
     // Fetch a timeline spanning from the root to some arbitrary node
-    pub fn get_timeline(&self, handle: &Handle) -> Timeline
+    // None if the node doesn't exist
+    pub fn get_timeline(&self, handle: &Handle) -> Option<Timeline>
     {
-        match self.get_node(handle) {
-            Some(node) => Timeline::new(&node, self),
-            None => Timeline{universes: vec![]}
-        }
+        Some(Timeline::new(&self.get_node(handle)?, self))
     }
 
-    pub fn update_multiverse(&mut self, handle: Handle, edits: &mut Vec<BranchParams>) {
-        match self.get_node_mut(&handle) {
-            Some(node) => {
-                match &mut node.delta {
-                    Some(deltas) => deltas.append(edits),
-                    None => node.delta = Some(edits.to_owned()),
-                }
-            }
-            None => ()
+    // Appends edits to an existing node, invalidating its cached universe and those of its descendants
+    pub fn update_multiverse(&mut self, handle: Handle, edits: &mut Vec<Delta>) -> bool {
+        let Some(node) = self.nodes.get(&handle) else {
+            return false;
+        };
+        let existing = node.delta.as_ref().map_or(0, |d| d.len());
+        for (i, delta) in edits.iter_mut().enumerate() {
+            delta.assign_id(|| new_id(self.physics.deterministic, handle.id, &format!("body-{}", existing + i)));
+        }
+        let Some(node) = self.get_node_mut(&handle) else {
+            return false;
+        };
+        match &mut node.delta {
+            Some(deltas) => deltas.append(edits),
+            None => node.delta = Some(edits.to_owned()),
         }
+        let node = node.clone();
+        self.node_store.save_handle(&node, handle);
+        node.clear_universe(self);
+        true
     }
 
     // handle is the Node handle
     pub fn get_universe(&self, handle: &Handle) -> Option<Universe> {
-        Some(self.get_node(handle)?.get_universe(&self))
+        Some(self.get_node(handle)?.get_universe(self))
     }
 
     pub fn get_nodes(&self) -> Vec<Handle> {
@@ -93,34 +156,319 @@ impl Multiverse {
         self.nodes.get_mut(handle)
     }
 
-    pub fn advance(&mut self, handle: &Handle, duration: i32) -> MultiverseNode {
+    // Returns the handle of the newly created node. A node has only one canonical next node, so advancing
+    // one that already has it is refused rather than orphaning the existing one.
+    pub fn advance(&mut self, handle: &Handle, duration: i32) -> Result<Handle, AdvanceError> {
+        if let Some(next) = self.nodes.get(handle).ok_or(AdvanceError::NotFound)?.next {
+            return Err(AdvanceError::HasNext(next));
+        }
         let new_node = MultiverseNode::new(Some(*handle), duration, vec![]);
-        let parent = self.nodes.get_mut(handle).unwrap();
-        parent.next = Some(*handle);
-        self.node_store.save_handle(&parent, *handle);
-        let h = self.node_store.save(new_node);
-        let node = self.node_store.get(&h).unwrap();
-        self.nodes.insert(h, node);
-        self.node_store.get(&h).unwrap()
+        let h = self.insert_node(new_node);
+        let parent = self.nodes.get_mut(handle).ok_or(AdvanceError::NotFound)?;
+        parent.next = Some(h);
+        self.node_store.save_handle(parent, *handle);
+        Ok(h)
     }
 
-    pub fn branch(&mut self, handle: &Handle, duration: i32, deltas: Vec<BranchParams>) {
+    // Returns the handle of the newly created node, or None if the parent doesn't exist
+    pub fn branch(&mut self, handle: &Handle, duration: i32, deltas: Vec<Delta>) -> Option<Handle> {
+        let mut parent = self.node_store.get(handle)?;
         let new_node = MultiverseNode::new(Some(*handle), duration, deltas);
-        let new_handle = self.node_store.save(new_node);
-        let mut parent = self.node_store.get(&handle).unwrap();
+        let new_handle = self.insert_node(new_node);
         parent.children.push(new_handle);
         self.node_store.save_handle(&parent, *handle);
-        let new_node = self.node_store.get(&new_handle).unwrap();
-        self.nodes.insert(new_handle, new_node);
+        self.nodes.insert(*handle, parent);
+        Some(new_handle)
+    }
+
+    // Branches one child per variant, returning their handles in the sweep's order, or None if the parent doesn't exist
+    pub fn sweep(&mut self, handle: &Handle, duration: i32, variants: Vec<Variant>) -> Option<Vec<Handle>> {
+        let id = self.new_id(handle, "sweep")?;
+        let children = variants.into_iter().map(|variant| {
+            let mut new_node = MultiverseNode::new(Some(*handle), duration, vec![variant.delta.into()]);
+            new_node.sweep = Some(SweepTag { sweep: id, coordinates: variant.coordinates });
+            new_node
+        }).collect();
+        self.add_children(handle, children)
+    }
+
+    // Branches the ensemble's members
+    pub fn ensemble(&mut self, handle: &Handle, duration: i32, ensemble: &Ensemble) -> Result<EnsembleCreated, EnsembleError> {
+        let universe = self.get_universe(handle).ok_or(EnsembleError::NotFound)?;
+        let id = self.new_id(handle, "ensemble").ok_or(EnsembleError::NotFound)?;
+        let mut ensemble = ensemble.clone();
+        if self.physics.deterministic && ensemble.seed.is_none() {
+            ensemble.seed = Some(determinism::derive_seed(id, "seed"));
+        }
+        let (seed, members) = ensemble.members(&universe)?;
+        let children = members.into_iter().enumerate().map(|(member, deltas)| {
+            let mut new_node = MultiverseNode::new(Some(*handle), duration, deltas.into_iter().map(Delta::from).collect());
+            new_node.ensemble = Some(EnsembleTag { ensemble: id, seed, member });
+            new_node
+        }).collect();
+        let members = self.add_children(handle, children).ok_or(EnsembleError::NotFound)?;
+        Ok(EnsembleCreated { ensemble: id, seed, members })
+    }
+
+    // Re-applies source's deltas as a new child of onto, advancing by duration or by source's own relative_age.
+    // None if either node doesn't exist.
+    pub fn rebase(&mut self, source: &Handle, onto: &Handle, duration: Option<i32>) -> Option<Handle> {
+        let source = self.nodes.get(source)?;
+        let deltas = source.delta.clone().unwrap_or_default();
+        let duration = duration.unwrap_or(source.relative_age);
+        self.branch(onto, duration, deltas)
+    }
+
+    // Collapses the chain from `from` down to its descendant `to` into `from`, which takes the chain's deltas,
+    // its summed relative_age and `to`'s descendants. Only chains whose deltas all land before any ticks pass
+    // can be squashed without changing the universe at the end, and the nodes in between can't have other descendants.
+    // Returns the squashed node and the handles that were removed.
+    pub fn squash(&mut self, from: &Handle, to: &Handle) -> Result<(MultiverseNode, Vec<Handle>), SquashError> {
+        let lineage = self.lineage(to).ok_or(SquashError::NotFound)?;
+        if !self.nodes.contains_key(from) {
+            return Err(SquashError::NotFound);
+        }
+        let start = lineage.iter().position(|h| h == from).ok_or(SquashError::NotDescendant)?;
+        let chain = &lineage[start..];
+        let mut deltas = vec![];
+        let mut age = 0;
+        for (i, handle) in chain.iter().enumerate() {
+            let node = self.nodes.get(handle).ok_or(SquashError::NotFound)?;
+            if i + 1 < chain.len() && node.descendants() != [chain[i + 1]] {
+                return Err(SquashError::SideBranches(*handle));
+            }
+            if let Some(delta) = &node.delta {
+                if age != 0 && !delta.is_empty() {
+                    return Err(SquashError::DeltasAfterTicks(*handle));
+                }
+                deltas.extend(delta.iter().cloned());
+            }
+            age += node.relative_age;
+        }
+        if chain.len() == 1 {
+            let node = self.nodes.get(from).ok_or(SquashError::NotFound)?.clone();
+            return Ok((node, vec![]));
+        }
+
+        let last = self.nodes.get(to).ok_or(SquashError::NotFound)?.clone();
+        let mut squashed = self.nodes.get(from).ok_or(SquashError::NotFound)?.clone();
+        squashed.delta = if deltas.is_empty() { None } else { Some(deltas) };
+        squashed.relative_age = age;
+        squashed.children = last.children.clone();
+        squashed.next = last.next;
+        // The bodies at the end of the chain are unchanged, but its ticks, substeps and events now cover the
+        // whole chain, so the squashed node's universe is recomputed when it's next needed
+        self.universe_store.delete_handle(squashed.universe);
+        self.hash_store.delete_handle(squashed.universe);
+        for child in last.descendants() {
+            if let Some(node) = self.nodes.get_mut(&child) {
+                node.parent = Some(*from);
+                self.node_store.save_handle(node, child);
+            }
+        }
+        let removed: Vec<Handle> = chain[1..].to_vec();
+        for handle in &removed {
+            if let Some(node) = self.nodes.remove(handle) {
+                self.universe_store.delete_handle(node.universe);
+                self.hash_store.delete_handle(node.universe);
+            }
+            self.node_store.delete_handle(*handle);
+        }
+        self.node_store.save_handle(&squashed, *from);
+        self.nodes.insert(*from, squashed.clone());
+        Ok((squashed, removed))
+    }
+
+    // Saves each node as a child of handle, returning their handles in order
+    fn add_children(&mut self, handle: &Handle, children: Vec<MultiverseNode>) -> Option<Vec<Handle>> {
+        let mut parent = self.node_store.get(handle)?;
+        let mut new_handles = vec![];
+        for new_node in children {
+            let new_handle = self.insert_node(new_node);
+            parent.children.push(new_handle);
+            new_handles.push(new_handle);
+        }
+        self.node_store.save_handle(&parent, *handle);
+        self.nodes.insert(*handle, parent);
+        Some(new_handles)
+    }
+
+    // Removes a node and everything that descends from it, returning the handles that were removed.
+    // The root can't be pruned.
+    pub fn prune(&mut self, handle: &Handle) -> Result<Vec<Handle>, PruneError> {
+        if self.root_node == Some(*handle) {
+            return Err(PruneError::Root);
+        }
+        let node = self.nodes.get(handle).ok_or(PruneError::NotFound)?.clone();
+        if let Some(parent_handle) = node.parent {
+            if let Some(parent) = self.nodes.get_mut(&parent_handle) {
+                parent.children.retain(|c| c != handle);
+                if parent.next == Some(*handle) {
+                    parent.next = None;
+                }
+                self.node_store.save_handle(parent, parent_handle);
+            }
+        }
+        let mut removed = vec![];
+        let mut pending = vec![*handle];
+        while let Some(h) = pending.pop() {
+            if let Some(n) = self.nodes.remove(&h) {
+                pending.extend(n.descendants());
+                self.universe_store.delete_handle(n.universe);
+                self.hash_store.delete_handle(n.universe);
+                self.node_store.delete_handle(h);
+                removed.push(h);
+            }
+        }
+        Ok(removed)
+    }
+
+    // Saves a new node and gives any bodies its deltas create their ids. A deterministic multiverse derives the
+    // node's handle from its parent's and how many descendants the parent already has, and everything else from that.
+    fn insert_node(&mut self, mut node: MultiverseNode) -> Handle {
+        let handle = if self.physics.deterministic {
+            let (namespace, taken) = match node.parent.and_then(|p| self.nodes.get(&p).map(|n| (p, n))) {
+                Some((parent, n)) => (parent.id, n.descendants().len()),
+                None => (Uuid::nil(), self.roots().len()),
+            };
+            // A pruned sibling's handle can come round again, but never a live node's
+            (taken..).map(|n| Handle::from(determinism::derive_id(namespace, &format!("node-{}", n))))
+                .find(|h| !self.nodes.contains_key(h))
+                .expect("Ran out of node handles")
+        } else {
+            Handle::new()
+        };
+        if self.physics.deterministic {
+            node.universe = Handle::from(determinism::derive_id(handle.id, "universe"));
+        }
+        if let Some(deltas) = &mut node.delta {
+            for (i, delta) in deltas.iter_mut().enumerate() {
+                delta.assign_id(|| new_id(self.physics.deterministic, handle.id, &format!("body-{}", i)));
+            }
+        }
+        self.node_store.save_handle(&node, handle);
+        self.nodes.insert(handle, node);
+        handle
+    }
+
+    // An id for something created from handle, such as a sweep or an ensemble, or None if the node doesn't exist
+    fn new_id(&self, handle: &Handle, kind: &str) -> Option<Uuid> {
+        let count = self.nodes.get(handle)?.descendants().len();
+        Some(new_id(self.physics.deterministic, handle.id, &format!("{}-{}", kind, count)))
+    }
+
+    // Records the hash of a universe computed in deterministic mode, or checks it against the one recorded before
+    fn record_hash(&self, handle: Handle, universe: &Universe) {
+        if !universe.physics.deterministic {
+            return;
+        }
+        let hash = determinism::content_hash(universe);
+        match self.hash_store.get(&handle) {
+            None => self.hash_store.save_handle(&UniverseHash { hash, mismatches: vec![] }, handle),
+            Some(mut recorded) if recorded.hash != hash => {
+                log::error!("Universe {} recomputed with hash {}, but it was {} when first computed", handle.id, hash, recorded.hash);
+                recorded.mismatches.push(hash);
+                self.hash_store.save_handle(&recorded, handle);
+            },
+            Some(_) => {},
+        }
+    }
+
+    // The hash recorded for a node's universe, computing the universe if needed. Outside deterministic mode
+    // nothing is recorded, so it's the hash of the universe as it is now. None if the node doesn't exist.
+    pub fn universe_hash(&self, handle: &Handle) -> Option<UniverseHash> {
+        let node = self.nodes.get(handle)?;
+        let universe = node.get_universe(self);
+        Some(self.hash_store.get(&node.universe).unwrap_or_else(|| UniverseHash {
+            hash: determinism::content_hash(&universe),
+            mismatches: vec![],
+        }))
+    }
+
+    // Every node reachable from a root, parents before children
+    pub fn export(&self, include_universes: bool) -> MultiverseExport {
+        let mut nodes = vec![];
+        let mut pending: Vec<Handle> = self.roots().into_iter().rev().collect();
+        while let Some(handle) = pending.pop() {
+            if let Some(node) = self.nodes.get(&handle) {
+                pending.extend(node.descendants().into_iter().rev());
+                nodes.push(ExportedNode {
+                    handle,
+                    node: node.clone(),
+                    universe: if include_universes { Some(node.get_universe(self)) } else { None },
+                });
+            }
+        }
+        MultiverseExport {
+            root_node: self.root_node,
+            nodes,
+        }
+    }
+
+    // Handles from the root down to and including handle, or None if it isn't a known node
+    pub fn lineage(&self, handle: &Handle) -> Option<Vec<Handle>> {
+        let node = self.nodes.get(handle)?;
+        let mut lineage = node.get_lineage(self);
+        lineage.reverse();
+        lineage.push(*handle);
+        Some(lineage)
+    }
+
+    // Closes both stores, returning how many nodes and cached universes they hold
+    pub fn close(self) -> Result<(usize, usize), String> {
+        let nodes = self.node_store.count();
+        let universes = self.universe_store.count();
+        self.node_store.close()?;
+        self.universe_store.close()?;
+        self.hash_store.close()?;
+        Ok((nodes, universes))
     }
 
     //pub fn edit_root(&self, )
 }
 
-#[derive(Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExportedNode
+{
+    pub handle: Handle,
+    pub node: MultiverseNode,
+    pub universe: Option<Universe>,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MultiverseExport
+{
+    pub root_node: Option<Handle>,
+    pub nodes: Vec<ExportedNode>,
+}
+
+// A name-derived id in deterministic mode, otherwise a random one
+fn new_id(deterministic: bool, namespace: Uuid, name: &str) -> Uuid {
+    if deterministic {
+        determinism::derive_id(namespace, name)
+    } else {
+        Uuid::new_v4()
+    }
+}
+
+impl Default for Multiverse {
+    fn default() -> Self {
+        Multiverse::new()
+    }
+}
+
+// The original delta: sets, then offsets, the target body's state, creating the body if it doesn't exist.
+// Unknown fields are rejected so a misspelt operation isn't mistaken for one of these.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BranchParams
 {
+    // May be left out when target_name is given
+    #[serde(default)]
     pub target_body: uuid::Uuid,
+    // Picks the body by name when no body has target_body's id. A body created for the delta takes this name.
+    #[serde(default)]
+    pub target_name: Option<String>,
     // d_ prefix stands for "delta"
     pub position: Option<Pos>,
     pub d_position: Option<Pos>,
@@ -128,6 +476,24 @@ pub struct BranchParams
     pub d_velocity: Option<Pos>,
     pub mass: Option<f64>,
     pub d_mas: Option<f64>,
+    // Places the body on this orbit instead of using position/velocity. The d_ deltas still apply on top.
+    #[serde(default)]
+    pub orbit: Option<OrbitSpec>,
+    // Metadata to set on the body. Properties are merged with the body's own, and setting one to null removes it.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub radius: Option<f64>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, serde_json::Value>,
+    // Pins the body, sets it free or drives it along a trajectory, applied after everything else
+    #[serde(default)]
+    pub motion: Option<Motion>,
+    // Makes the body a test particle, which feels gravity but exerts none, or a massive body again
+    #[serde(default)]
+    pub test_particle: Option<bool>,
 }
 
 impl BranchParams {
@@ -141,20 +507,120 @@ impl BranchParams {
         target.position += self.d_position.unwrap_or_default();
         target.velocity += self.d_velocity.unwrap_or_default();
         target.mass += self.d_mas.unwrap_or_default();
+        target.set_metadata(&self.name, &self.color, self.radius, &self.properties);
+        target.test_particle = self.test_particle.unwrap_or(target.test_particle);
     }
 
     pub fn new_body(&self) -> Body {
-        let mut b = Body::new();
+        let mut b = Body { id: self.target_body, name: self.target_name.clone(), ..Default::default() };
+        b.set_metadata(&self.name, &self.color, self.radius, &self.properties);
+        b.test_particle = self.test_particle.unwrap_or_default();
         b.position = self.position.unwrap_or_default() + self.d_position.unwrap_or_default();
         b.velocity = self.velocity.unwrap_or_default() + self.d_velocity.unwrap_or_default();
         b.mass = self.mass.unwrap_or_default() + self.d_mas.unwrap_or_default();
         b
     }
 
+    // The body the delta applies to: by id, then by name. None means it should create one.
+    // Err if the name is ambiguous.
+    fn find_target(&self, target: &Universe) -> Result<Option<usize>, String> {
+        if let Some(i) = target.bodies.iter().position(|b| b.id == self.target_body) {
+            return Ok(Some(i));
+        }
+        let Some(name) = &self.target_name else {
+            return Ok(None);
+        };
+        match target.find_by_name(name)[..] {
+            [] => Ok(None),
+            [i] => Ok(Some(i)),
+            _ => Err(format!("Several bodies are named {}", name)),
+        }
+    }
+
     pub fn apply_universe(&self, target: &mut Universe) {
-        match target.get_body_mut(self.target_body) {
-            Some(body) => self.apply_body(body),
-            None => target.add_body(self.new_body()),
+        let found = match self.find_target(target) {
+            Ok(found) => found,
+            Err(e) => {
+                log::warn!("{}, skipping the delta", e);
+                return;
+            },
+        };
+        let index = match found {
+            Some(i) => {
+                self.apply_body(&mut target.bodies[i]);
+                i
+            },
+            None => {
+                target.add_body(self.new_body());
+                target.bodies.len() - 1
+            },
+        };
+        if let Some(orbit) = &self.orbit {
+            // Orbits are resolved against the parent's state at branch time, with the d_ deltas on top
+            match orbit::place(target, index, orbit) {
+                Ok(()) => {
+                    let body = &mut target.bodies[index];
+                    body.position += self.d_position.unwrap_or_default();
+                    body.velocity += self.d_velocity.unwrap_or_default();
+                },
+                Err(e) => log::warn!("{}, leaving it in place", e),
+            }
+        }
+        if let Some(motion) = &self.motion {
+            delta::set_motion(target, index, motion);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SquashError {
+    NotFound,
+    // The end of the chain doesn't descend from its start
+    NotDescendant,
+    // This node in the chain has descendants outside it
+    SideBranches(Handle),
+    // This node's deltas land after the chain has already ticked
+    DeltasAfterTicks(Handle),
+}
+
+#[derive(Debug)]
+pub enum AdvanceError {
+    NotFound,
+    // The node's existing next node
+    HasNext(Handle),
+}
+
+impl fmt::Display for AdvanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdvanceError::NotFound => write!(f, "Node not found"),
+            AdvanceError::HasNext(next) => write!(f, "The node already has a next node, {}", next.id),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PruneError {
+    NotFound,
+    Root,
+}
+
+impl fmt::Display for PruneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PruneError::NotFound => write!(f, "Node not found"),
+            PruneError::Root => write!(f, "The root node can't be pruned"),
+        }
+    }
+}
+
+impl fmt::Display for SquashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SquashError::NotFound => write!(f, "Node not found"),
+            SquashError::NotDescendant => write!(f, "The end of the chain doesn't descend from its start"),
+            SquashError::SideBranches(h) => write!(f, "Node {} has descendants outside the chain", h.id),
+            SquashError::DeltasAfterTicks(h) => write!(f, "Node {} applies deltas after the chain has advanced, so squashing would change the universe", h.id),
         }
     }
 }
@@ -165,7 +631,7 @@ pub struct MultiverseNode
     // The parent we base ourselves off of
     pub parent: Option<Handle>,
     // Did we mutate any data compared to our parent?
-    pub delta: Option<Vec<BranchParams>>,
+    pub delta: Option<Vec<Delta>>,
     // The next thing to happen cannonically
     pub next: Option<Handle>,
     // Possible future universes
@@ -173,28 +639,38 @@ pub struct MultiverseNode
     // The universe at this moment in time
     pub universe: Handle,
     // How many ticks do we advance relative to our parent?
-    pub relative_age: i32
+    pub relative_age: i32,
+    // Where this node sits in the parameter sweep that created it, if any
+    #[serde(default)]
+    pub sweep: Option<SweepTag>,
+    // Which Monte Carlo ensemble this node is a member of, if any
+    #[serde(default)]
+    pub ensemble: Option<EnsembleTag>,
 }
 
 impl MultiverseNode {
-    pub fn new(parent: Option<Handle>, age: i32, deltas: Vec<BranchParams>) -> MultiverseNode {
+    pub fn new(parent: Option<Handle>, age: i32, deltas: Vec<Delta>) -> MultiverseNode {
         if deltas.is_empty() {
             MultiverseNode{
-                parent: parent,
+                parent,
                 delta: None,
                 next: None,
                 children: vec![],
                 universe: Handle::new(),
-                relative_age: age
+                relative_age: age,
+                sweep: None,
+                ensemble: None,
             }
         } else {
             MultiverseNode{
-                parent: parent,
+                parent,
                 delta: Some(deltas),
                 next: None,
                 children: vec![],
                 universe: Handle::new(),
-                relative_age: age
+                relative_age: age,
+                sweep: None,
+                ensemble: None,
             }
         }
     }
@@ -203,11 +679,11 @@ impl MultiverseNode {
         let mut ancestors = vec![];
         let mut me = self;
         loop {
-            match (me.parent) {
+            match me.parent {
                 None => return ancestors,
                 Some(handle) => ancestors.push(handle),
             }
-            match (multiverse.nodes.get(&me.parent.unwrap())) {
+            match multiverse.nodes.get(&me.parent.unwrap()) {
                 // Really, we should panic because this is an incorrect state
                 None => return  ancestors,
                 Some(node) => me = node,
@@ -216,61 +692,434 @@ impl MultiverseNode {
     }
 
     pub fn get_parent(&self, multiverse: &Multiverse) -> Option<MultiverseNode> {
-        if self.parent.is_none() {
-            return None
-        }
-        match multiverse.nodes.get(&self.parent.unwrap()) {
-            Some(node) => Some(node.clone()),
-            _ => None
-        }
+        multiverse.nodes.get(&self.parent?).cloned()
     }
 
-    pub fn calculate_universe(&self, multiverse: &Multiverse) -> Universe {
+    // The parent's universe with our deltas applied, before any time has passed
+    pub fn initial_universe(&self, multiverse: &Multiverse) -> Universe {
         let mut new_universe = match self.get_parent(multiverse) {
-            None => Universe::new(),
+            None => Universe::with_physics(multiverse.physics),
             Some(parent) => {
                 parent.get_universe(multiverse)
             }
         };
-        match &self.delta {
-            Some(params) => {
-                for param in params {
-                    param.apply_universe(&mut new_universe);
-                }
+        if let Some(params) = &self.delta {
+            for param in params {
+                param.apply_universe(&mut new_universe);
             }
-            _ => (),
         }
+        new_universe
+    }
+
+    pub fn calculate_universe(&self, multiverse: &Multiverse) -> Universe {
+        let mut new_universe = self.initial_universe(multiverse);
+        // Ticks, substeps and events are reported per node, counting only this node's ticks
+        new_universe.ticks = 0;
+        new_universe.substeps = 0;
+        new_universe.events.clear();
         new_universe.tick_for(self.relative_age);
+        // Same node, same universe, down to its id
+        new_universe.id = self.universe.id;
+        multiverse.record_hash(self.universe, &new_universe);
         multiverse.universe_store.save_handle(&new_universe, self.universe);
         for child_handle in &self.children {
-            match multiverse.nodes.get(&child_handle) {
-                Some(child) => {
-                    child.clear_universe(multiverse);
-                },
-                None => (),
-            };
+            if let Some(child) = multiverse.nodes.get(child_handle) {
+                child.evict_universe(multiverse);
+            }
         }
         new_universe
     }
 
     pub fn get_universe(&self, multiverse: &Multiverse) -> Universe {
         match multiverse.universe_store.get(&self.universe) {
-            Some(u) => return u,
+            Some(u) => u,
             None => self.calculate_universe(multiverse)
         }
     }
 
+    // For when the universe has changed: drops the cached copies and recorded hashes of this node and its descendants
     pub fn clear_universe(&self, multiverse: &Multiverse) {
         multiverse.universe_store.delete_handle(self.universe);
-        for child in self.children.iter() {
-            match multiverse.get_node(child)
-            {
-                Some(node) => node.clear_universe(multiverse),
-                None => (),
-            };
+        multiverse.hash_store.delete_handle(self.universe);
+        for child in self.descendants() {
+            if let Some(node) = multiverse.get_node(&child) {
+                node.clear_universe(multiverse);
+            }
+        }
+    }
+
+    // Drops the cached copies but keeps the hashes, so the recomputed universes are checked against them
+    pub fn evict_universe(&self, multiverse: &Multiverse) {
+        multiverse.universe_store.delete_handle(self.universe);
+        for child in self.descendants() {
+            if let Some(node) = multiverse.get_node(&child) {
+                node.evict_universe(multiverse);
+            }
+        }
+    }
+
+    // Branched children plus the canonical next node, if any
+    pub fn descendants(&self) -> Vec<Handle> {
+        self.children.iter().copied().chain(self.next).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use crate::{delta::Operation, events::{Detector, EventKind}, scenario::TwoBody, sweep::{Sweep, SweepAxis, SweepField, SweepValues}};
+
+    fn universe_json(multiverse: &Multiverse, handle: &Handle) -> serde_json::Value {
+        let node = multiverse.nodes.get(handle).expect("Node should exist");
+        serde_json::to_value(node.calculate_universe(multiverse)).expect("Failed to serialize universe")
+    }
+
+    #[test]
+    fn recalculating_a_universe_is_deterministic() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+
+        // No target_body, so the node picks the new body's id
+        let new_body = BranchParams { mass: Some(0.1), position: Some(Pos{ x: 3.0, y: 0.0, z: 0.0 }), ..Default::default() };
+        let child = multiverse.branch(&root, 50, vec![new_body.into()]).expect("Root should exist");
+        let root_ids: Vec<Uuid> = multiverse.get_universe(&root).expect("Root should exist").bodies.iter().map(|b| b.id).collect();
+        let created = multiverse.get_universe(&child).expect("Child should exist").bodies.iter()
+            .map(|b| b.id)
+            .find(|id| !root_ids.contains(id))
+            .expect("The child should have created a body");
+        let offset = Operation::OffsetState { target_body: created, d_position: None, d_velocity: None, d_mass: Some(0.1) };
+        let grandchild = multiverse.branch(&child, 50, vec![offset.into()]).expect("Child should exist");
+
+        let first = universe_json(&multiverse, &grandchild);
+        // Drop every cached universe so the whole chain is rebuilt from the deltas
+        multiverse.nodes[&root].clear_universe(&multiverse);
+        let second = universe_json(&multiverse, &grandchild);
+        assert_eq!(first, second);
+
+        // The offset found the body its parent created instead of missing it and making another
+        let universe = multiverse.get_universe(&grandchild).expect("Grandchild should exist");
+        assert_eq!(universe.bodies.len(), 3);
+        assert!((universe.get_body(created).expect("Created body should survive").mass - 0.2).abs() < 1e-12);
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn adaptive_steps_keep_an_eccentric_orbit_within_tolerance() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        // A hundred ticks per orbit, far too few for fixed steps through a periapsis at a = 0.1
+        let period = 2.0 * std::f64::consts::PI / 1.001f64.sqrt();
+        let physics = Physics { gravitational_constant: 1.0, timestep: period / 100.0, ..Default::default() };
+        let scenario = Scenario::TwoBody(TwoBody { eccentricity: 0.9, ..Default::default() });
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &scenario);
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let separation = |universe: &Universe| universe.bodies[1].position - universe.bodies[0].position;
+        let start = separation(&multiverse.get_universe(&root).expect("Root should exist"));
+
+        let orbit = |multiverse: &mut Multiverse, tolerance| {
+            let node = multiverse.branch(&root, 100, vec![Operation::SetTolerance { tolerance }.into()]).expect("Root should exist");
+            multiverse.get_universe(&node).expect("Node should exist")
+        };
+        let precise = orbit(&mut multiverse, Some(1e-10));
+        let loose = orbit(&mut multiverse, Some(1e-6));
+        let fixed = orbit(&mut multiverse, None);
+
+        // After a whole orbit the secondary is back at periapsis
+        let error = |universe: &Universe| separation(universe).dist(start);
+        assert!(error(&precise) < 1e-6, "{}", error(&precise));
+        assert!(error(&precise) < error(&loose));
+        assert!(error(&loose) < 1e-2 && error(&fixed) > 1e-1, "{} {}", error(&loose), error(&fixed));
+        // Every tick was split up, and more finely for the tighter tolerance
+        assert_eq!((precise.ticks, fixed.substeps), (100, 100));
+        assert!(precise.substeps > loose.substeps && loose.substeps > 100);
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn advancing_keeps_an_existing_next_node() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let next = multiverse.advance(&root, 10).expect("Root should exist");
+
+        assert!(matches!(multiverse.advance(&root, 20), Err(AdvanceError::HasNext(h)) if h == next));
+        assert!(matches!(multiverse.advance(&Handle::new(), 20), Err(AdvanceError::NotFound)));
+        assert_eq!(multiverse.nodes[&root].descendants(), [next]);
+        assert_eq!(multiverse.get_nodes().len(), 2);
+        // Its own next node can still be advanced
+        assert!(multiverse.advance(&next, 10).is_ok());
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn pruning_removes_descendants_but_not_the_root() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let child = multiverse.advance(&root, 10).expect("Root should exist");
+        let grandchild = multiverse.branch(&child, 10, vec![]).expect("Child should exist");
+        let sibling = multiverse.branch(&root, 10, vec![]).expect("Root should exist");
+
+        assert!(matches!(multiverse.prune(&root), Err(PruneError::Root)));
+        let mut removed = multiverse.prune(&child).expect("Child should exist");
+        removed.sort_by_key(|h| h.id);
+        let mut expected = vec![child, grandchild];
+        expected.sort_by_key(|h| h.id);
+        assert_eq!(removed, expected);
+        assert!(multiverse.get_timeline(&grandchild).is_none());
+        assert!(matches!(multiverse.prune(&child), Err(PruneError::NotFound)));
+        let root_node = multiverse.get_node(&root).expect("Root should survive");
+        assert_eq!((root_node.next, root_node.children), (None, vec![sibling]));
+        assert!(multiverse.get_timeline(&sibling).is_some());
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn detectors_record_each_nodes_own_events() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        // Starts at periapsis 0.5 and goes out to apoapsis 1.5 with a period of 2pi, about 628 ticks
+        let scenario = Scenario::TwoBody(TwoBody { eccentricity: 0.5, ..Default::default() });
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &scenario);
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let detectors = Operation::SetDetectors { detectors: vec![Detector::Apsides { primary: None }, Detector::CloseApproach { distance: 0.6 }] };
+        let child = multiverse.branch(&root, 1000, vec![detectors.into()]).expect("Root should exist");
+        let grandchild = multiverse.advance(&child, 700).expect("Child should exist");
+
+        let kinds = |handle: &Handle| -> Vec<(&'static str, u64, f64)> {
+            multiverse.get_universe(handle).expect("Node should exist").events.iter().map(|e| match e.kind {
+                EventKind::Periapsis { distance } | EventKind::Apoapsis { distance } | EventKind::CloseApproach { distance, .. } => (e.kind.name(), e.tick, distance),
+                _ => panic!("Unexpected event {:?}", e),
+            }).collect()
+        };
+        let child_events = kinds(&child);
+        let names: Vec<&str> = child_events.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(names, vec!["apoapsis", "periapsis", "close_approach", "apoapsis"]);
+        assert!((child_events[0].2 - 1.5).abs() < 1e-2);
+        assert!((child_events[1].2 - 0.5).abs() < 1e-2);
+        assert_eq!(child_events[1].1, child_events[2].1);
+
+        // The grandchild inherits the detectors, but only records what happened in its own ticks
+        let grandchild_events = kinds(&grandchild);
+        let names: Vec<&str> = grandchild_events.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(names, vec!["periapsis", "close_approach", "apoapsis"]);
+        assert!(grandchild_events.iter().all(|(_, tick, _)| (1..=700).contains(tick)));
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn target_names_pick_a_unique_body_or_create_one() {
+        let mut universe = Universe::default();
+        for name in ["Earth", "Moon", "Moon"] {
+            universe.add_body(Body { mass: 1.0, name: Some(String::from(name)), ..Body::new() });
         }
-        if self.next.is_some() {
-            let next = multiverse.get_node(&self.next.unwrap());
+        let by_name = |name: &str| BranchParams { target_name: Some(String::from(name)), d_mas: Some(0.5), ..Default::default() };
+
+        by_name("Earth").apply_universe(&mut universe);
+        assert_eq!(universe.bodies.iter().map(|b| b.mass).collect::<Vec<_>>(), [1.5, 1.0, 1.0]);
+
+        // Ambiguous names are skipped rather than guessed
+        by_name("Moon").apply_universe(&mut universe);
+        assert_eq!(universe.bodies.iter().map(|b| b.mass).collect::<Vec<_>>(), [1.5, 1.0, 1.0]);
+
+        // An id still wins over a name, even an ambiguous one
+        let moon = universe.bodies[2].id;
+        BranchParams { target_body: moon, ..by_name("Moon") }.apply_universe(&mut universe);
+        assert_eq!(universe.bodies.iter().map(|b| b.mass).collect::<Vec<_>>(), [1.5, 1.0, 1.5]);
+
+        // A name nothing has makes a new body with it
+        by_name("Pluto").apply_universe(&mut universe);
+        assert_eq!(universe.bodies.len(), 4);
+        assert_eq!((universe.bodies[3].name.as_deref(), universe.bodies[3].mass), (Some("Pluto"), 0.5));
+    }
+
+    fn positions(multiverse: &Multiverse, handle: &Handle) -> Vec<(Uuid, Pos, Pos)> {
+        multiverse.get_universe(handle).expect("Node should exist").bodies.iter().map(|b| (b.id, b.position, b.velocity)).collect()
+    }
+
+    fn same_positions(a: &[(Uuid, Pos, Pos)], b: &[(Uuid, Pos, Pos)]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.0 == b.0 && (a.1 - b.1).is_zero() && (a.2 - b.2).is_zero())
+    }
+
+    #[test]
+    fn rebasing_reapplies_a_nodes_deltas_onto_another() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let body = multiverse.get_universe(&root).expect("Root should exist").bodies[1].id;
+        let offset = || Delta::from(Operation::OffsetState { target_body: body, d_position: None, d_velocity: Some(Pos{ x: 0.0, y: 0.0, z: 0.1 }), d_mass: None });
+
+        let onto = multiverse.advance(&root, 30).expect("Root should exist");
+        let source = multiverse.branch(&root, 40, vec![offset()]).expect("Root should exist");
+        let rebased = multiverse.rebase(&source, &onto, None).expect("Both nodes exist");
+        let direct = multiverse.branch(&onto, 40, vec![offset()]).expect("Onto should exist");
+        assert_eq!(multiverse.nodes[&rebased].parent, Some(onto));
+        assert!(same_positions(&positions(&multiverse, &rebased), &positions(&multiverse, &direct)));
+        assert_eq!(multiverse.get_universe(&rebased).expect("Rebased node should exist").ticks, 40);
+
+        let shortened = multiverse.rebase(&source, &onto, Some(10)).expect("Both nodes exist");
+        assert_eq!(multiverse.get_universe(&shortened).expect("Rebased node should exist").ticks, 10);
+        assert!(multiverse.rebase(&Handle::new(), &onto, None).is_none());
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn squashing_keeps_the_end_state_and_counts_the_whole_chain() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, deterministic: true, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let body = multiverse.get_universe(&root).expect("Root should exist").bodies[1].id;
+        let offset = |d_mass| Delta::from(Operation::OffsetState { target_body: body, d_position: None, d_velocity: None, d_mass: Some(d_mass) });
+
+        // Every delta lands before the chain ticks, so it can be squashed
+        let from = multiverse.branch(&root, 0, vec![offset(0.1)]).expect("Root should exist");
+        let middle = multiverse.branch(&from, 30, vec![offset(0.2)]).expect("From should exist");
+        let to = multiverse.advance(&middle, 20).expect("Middle should exist");
+        let leaf = multiverse.advance(&to, 10).expect("To should exist");
+        let end = positions(&multiverse, &to);
+        let leaf_before = universe_json(&multiverse, &leaf);
+
+        assert!(matches!(multiverse.squash(&middle, &from), Err(SquashError::NotDescendant)));
+        let (squashed, mut removed) = multiverse.squash(&from, &to).expect("The chain can be squashed");
+        removed.sort_by_key(|h| h.id);
+        let mut expected = vec![middle, to];
+        expected.sort_by_key(|h| h.id);
+        assert_eq!(removed, expected);
+        assert_eq!((squashed.relative_age, squashed.delta.as_ref().map(|d| d.len())), (50, Some(2)));
+        assert_eq!(squashed.descendants(), [leaf]);
+        assert_eq!(multiverse.nodes[&leaf].parent, Some(from));
+
+        // Same bodies at the end, but the counters now cover all 50 ticks rather than the last node's 20
+        let universe = multiverse.get_universe(&from).expect("From should exist");
+        assert!(same_positions(&positions(&multiverse, &from), &end));
+        assert!((universe.get_body(body).expect("Body should survive").mass - (1e-3 + 0.3)).abs() < 1e-12);
+        assert_eq!(universe.ticks, 50);
+        assert!(universe.substeps >= 50);
+
+        // The leaf recomputes to what it was, and matches its recorded hash
+        multiverse.nodes[&root].evict_universe(&multiverse);
+        assert_eq!(universe_json(&multiverse, &leaf), leaf_before);
+        assert!(multiverse.universe_hash(&leaf).expect("Leaf should exist").mismatches.is_empty());
+
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    // Builds the same small tree in a fresh deterministic multiverse, returning it with its data dir and the leaf
+    fn deterministic_multiverse() -> (Multiverse, PathBuf, Handle) {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics { gravitational_constant: 1.0, timestep: 0.01, deterministic: true, ..Default::default() };
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let new_body = BranchParams { mass: Some(0.1), position: Some(Pos{ x: 3.0, y: 0.0, z: 0.0 }), ..Default::default() };
+        let child = multiverse.branch(&root, 50, vec![new_body.into()]).expect("Root should exist");
+        let ensemble = Ensemble { count: 2, seed: None, perturbations: vec![] };
+        let members = multiverse.ensemble(&child, 50, &ensemble).expect("Child should exist").members;
+        (multiverse, data_dir, members[1])
+    }
+
+    #[test]
+    fn deterministic_multiverses_agree_and_verify_recomputes() {
+        let (mut a, dir_a, leaf_a) = deterministic_multiverse();
+        let (b, dir_b, leaf_b) = deterministic_multiverse();
+        assert_eq!(leaf_a, leaf_b);
+        assert_eq!(universe_json(&a, &leaf_a), universe_json(&b, &leaf_b));
+
+        // Evicting and recomputing the whole chain matches what was recorded
+        let root = a.root_node.expect("Seeding should create a root");
+        a.nodes[&root].evict_universe(&a);
+        universe_json(&a, &leaf_a);
+        assert!(a.universe_hash(&leaf_a).expect("Leaf should exist").mismatches.is_empty());
+
+        // A recompute that disagrees with the recorded hash is flagged
+        let universe = a.nodes[&leaf_a].universe;
+        a.hash_store.save_handle(&UniverseHash { hash: String::from("0"), mismatches: vec![] }, universe);
+        a.nodes[&leaf_a].evict_universe(&a);
+        let recorded = a.universe_hash(&leaf_a).expect("Leaf should exist");
+        assert_eq!(recorded.hash, "0");
+        assert_eq!(recorded.mismatches, vec![determinism::content_hash(&a.get_universe(&leaf_a).expect("Leaf should exist"))]);
+
+        // Edits change the universe, so they drop the recorded hash rather than flagging the next recompute
+        let offset = BranchParams { target_body: a.get_universe(&leaf_a).expect("Leaf should exist").bodies[0].id, d_mas: Some(0.1), ..Default::default() };
+        a.update_multiverse(leaf_a, &mut vec![offset.into()]);
+        assert!(a.universe_hash(&leaf_a).expect("Leaf should exist").mismatches.is_empty());
+
+        for (multiverse, dir) in [(a, dir_a), (b, dir_b)] {
+            multiverse.close().expect("Failed to close stores");
+            fs::remove_dir_all(&dir).expect("Failed to remove test data");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn universes_cached_under_other_physics_are_dropped() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics::default();
+        let mut multiverse = Multiverse::open_seeded(&data_dir, physics, &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let next = multiverse.advance(&root, 5).expect("Root should exist");
+        let before = positions(&multiverse, &next);
+        multiverse.universe_hash(&next).expect("Node should exist");
+        multiverse.close().expect("Failed to close stores");
+
+        // The same version keeps the cache
+        let multiverse = Multiverse::open(&data_dir, physics);
+        assert!(multiverse.universe_store.count() > 0);
+        multiverse.close().expect("Failed to close stores");
+
+        // A missing or older marker drops it, and the universes come back from a recompute
+        for marker in [None, Some("0")] {
+            match marker {
+                Some(version) => fs::write(data_dir.join(PHYSICS_VERSION_FILE), version).expect("Failed to write marker"),
+                None => fs::remove_file(data_dir.join(PHYSICS_VERSION_FILE)).expect("Failed to remove marker"),
+            }
+            let multiverse = Multiverse::open(&data_dir, physics);
+            assert_eq!((multiverse.universe_store.count(), multiverse.hash_store.count()), (0, 0));
+            assert_eq!(fs::read_to_string(data_dir.join(PHYSICS_VERSION_FILE)).expect("Marker should be written"), PHYSICS_VERSION.to_string());
+            assert!(same_positions(&before, &positions(&multiverse, &next)));
+            multiverse.close().expect("Failed to close stores");
+        }
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+
+    #[test]
+    fn a_sweep_tags_its_children_with_one_id() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let mut multiverse = Multiverse::open_seeded(&data_dir, Physics::default(), &Scenario::TwoBody(TwoBody::default()));
+        let root = multiverse.root_node.expect("Seeding should create a root");
+        let sweep = Sweep {
+            template: BranchParams::default(),
+            axes: vec![SweepAxis { field: SweepField::Mass, component: None, values: SweepValues::List { values: vec![1.0, 2.0, 3.0] } }],
+        };
+        let first = multiverse.sweep(&root, 0, sweep.variants().expect("Sweep should be valid")).expect("Root should exist");
+        let second = multiverse.sweep(&root, 0, sweep.variants().expect("Sweep should be valid")).expect("Root should exist");
+        let tags = |handles: &[Handle]| -> Vec<SweepTag> {
+            handles.iter().map(|h| multiverse.get_node(h).expect("Child should exist").sweep.expect("Child should be tagged")).collect()
+        };
+        let (first, second) = (tags(&first), tags(&second));
+        assert!(first.iter().all(|t| t.sweep == first[0].sweep));
+        assert!(second.iter().all(|t| t.sweep == second[0].sweep));
+        assert_ne!(first[0].sweep, second[0].sweep);
+        assert_eq!(first.iter().map(|t| t.coordinates[0].value).collect::<Vec<_>>(), [1.0, 2.0, 3.0]);
+        multiverse.close().expect("Failed to close stores");
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+}
@@ -1,34 +1,197 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::{fmt, path::Path, sync::mpsc::{Receiver, Sender}};
 
-use crate::{handle::Handle, multiverse::{BranchParams, Multiverse, MultiverseNode}, simulation::Universe, timeline::Timeline};
+use uuid::Uuid;
+
+use crate::{delta::Delta, determinism::UniverseHash, diagnostics::{self, DiagnosticsSample}, divergence::{self, DivergenceOptions, DivergenceReport}, ensemble::{self, Ensemble, EnsembleCreated, EnsembleError, EnsembleStatistics}, handle::Handle, multiverse::{AdvanceError, Multiverse, MultiverseNode, PruneError, SquashError}, scenario::Scenario, simulation::{Physics, Universe}, sweep::Variant, timeline::Timeline};
 
 pub enum MultiverseCommand {
-    // Node handle
-    AdvanceNode((Handle, i32)),
+    // Node handle, reply is the handle of the new node
+    AdvanceNode((Handle, i32, Sender<Result<Handle, AdvanceError>>)),
     // Universe handle
     GetUniverse((Handle, Sender<Option<Universe>>)),
     GetNodes(Sender<Vec<Handle>>),
-    GetTimneline((Handle, Sender<Timeline>)),
+    GetTimneline((Handle, Sender<Option<Timeline>>)),
     GetNode((Handle, Sender<Option<MultiverseNode>>)),
-    Branch((Handle, Vec<BranchParams>, i32))
+    // Node handle, reply is the handle of the new node
+    Branch((Handle, Vec<Delta>, i32, Sender<Option<Handle>>)),
+    // Node handle, reply is the handles of the new nodes in the sweep's order
+    Sweep((Handle, Vec<Variant>, i32, Sender<Option<Vec<Handle>>>)),
+    // Node handle, reply describes the new ensemble
+    CreateEnsemble((Handle, Ensemble, i32, Sender<Result<EnsembleCreated, EnsembleError>>)),
+    // Ensemble id and the age to sample at
+    EnsembleStatistics((Uuid, Option<i32>, Sender<Option<EnsembleStatistics>>)),
+    // Source node, the node to rebase it onto and an optional duration, reply is the handle of the new node
+    Rebase((Handle, Handle, Option<i32>, Sender<Option<Handle>>)),
+    // First and last node of the chain, reply is the squashed node
+    Squash((Handle, Handle, Sender<Result<MultiverseNode, SquashError>>)),
+    // Node handle, reply is the handles of the removed nodes
+    Prune((Handle, Sender<Result<Vec<Handle>, PruneError>>)),
+    // Node handle, reply is whether the node existed
+    EditNode((Handle, Vec<Delta>, Sender<bool>)),
+    // Node handle and optional sampling interval in ticks
+    TrackDiagnostics((Handle, Option<i32>, Sender<Option<Vec<DiagnosticsSample>>>)),
+    // The two node handles to compare
+    TrackDivergence((Handle, Handle, DivergenceOptions, Sender<Option<DivergenceReport>>)),
+    // Node handle, reply is the content hash of its universe and any mismatches found on recomputing it
+    GetUniverseHash((Handle, Sender<Option<UniverseHash>>)),
+    GetRoots(Sender<Vec<Handle>>),
+    // Reply is the handle of the new root
+    CreateRoot((Scenario, Sender<Handle>)),
+    // Finish everything already queued, then close the stores and stop the thread
+    Shutdown,
+}
+
+#[derive(Debug, Default)]
+pub struct ShutdownSummary {
+    pub commands_processed: usize,
+    pub nodes_created: usize,
+    pub nodes_edited: usize,
+    // What the stores held when they were closed
+    pub nodes_stored: usize,
+    pub universes_stored: usize,
+    pub close_error: Option<String>,
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "processed {} commands ({} nodes created, {} edited); {} nodes and {} cached universes persisted",
+            self.commands_processed, self.nodes_created, self.nodes_edited, self.nodes_stored, self.universes_stored)?;
+        if let Some(e) = &self.close_error {
+            write!(f, "; failed to close stores cleanly: {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs until a Shutdown command arrives or every sender is dropped
+pub fn start_multiverse(rx: Receiver<MultiverseCommand>, data_dir: &Path, physics: Physics, scenario: &Scenario) -> ShutdownSummary {
+    let mut multiverse = Multiverse::open_seeded(data_dir, physics, scenario);
+    let mut summary = ShutdownSummary::default();
+    while let Ok(cmd) = rx.recv() {
+        if let MultiverseCommand::Shutdown = cmd {
+            // Anything queued behind the shutdown request was still sent before we stopped
+            while let Ok(cmd) = rx.try_recv() {
+                run_command(&mut multiverse, cmd, &mut summary);
+            }
+            break;
+        }
+        run_command(&mut multiverse, cmd, &mut summary);
+    }
+    match multiverse.close() {
+        Ok((nodes, universes)) => {
+            summary.nodes_stored = nodes;
+            summary.universes_stored = universes;
+        },
+        Err(e) => summary.close_error = Some(e),
+    }
+    summary
+}
+
+fn run_command(multiverse: &mut Multiverse, cmd: MultiverseCommand, summary: &mut ShutdownSummary) {
+    summary.commands_processed += 1;
+    // A closed reply channel just means the requester gave up waiting
+    match cmd {
+        MultiverseCommand::AdvanceNode((handle, duration, tx)) => {
+            let new_handle = multiverse.advance(&handle, duration);
+            summary.nodes_created += new_handle.iter().count();
+            let _ = tx.send(new_handle);
+        },
+        MultiverseCommand::GetUniverse((handle, tx)) => {let _ = tx.send(multiverse.get_universe(&handle));},
+        MultiverseCommand::GetNodes(sender) => {let _ = sender.send(multiverse.get_nodes());},
+        MultiverseCommand::GetTimneline((handle, tx)) => {let _ = tx.send(multiverse.get_timeline(&handle));},
+        MultiverseCommand::GetNode((handle, tx)) => {let _ = tx.send(multiverse.get_node(&handle));}
+        MultiverseCommand::Branch((handle, params, duration, tx)) => {
+            let new_handle = multiverse.branch(&handle, duration, params);
+            summary.nodes_created += new_handle.iter().count();
+            let _ = tx.send(new_handle);
+        }
+        MultiverseCommand::Sweep((handle, variants, duration, tx)) => {
+            let new_handles = multiverse.sweep(&handle, duration, variants);
+            summary.nodes_created += new_handles.as_ref().map_or(0, |h| h.len());
+            let _ = tx.send(new_handles);
+        }
+        MultiverseCommand::CreateEnsemble((handle, spec, duration, tx)) => {
+            let created = multiverse.ensemble(&handle, duration, &spec);
+            summary.nodes_created += created.as_ref().map_or(0, |c| c.members.len());
+            let _ = tx.send(created);
+        }
+        MultiverseCommand::EnsembleStatistics((id, age, tx)) => {let _ = tx.send(ensemble::statistics(multiverse, id, age));}
+        MultiverseCommand::Rebase((source, onto, duration, tx)) => {
+            let new_handle = multiverse.rebase(&source, &onto, duration);
+            summary.nodes_created += new_handle.iter().count();
+            let _ = tx.send(new_handle);
+        }
+        MultiverseCommand::Squash((from, to, tx)) => {
+            let squashed = multiverse.squash(&from, &to);
+            if squashed.is_ok() {
+                summary.nodes_edited += 1;
+            }
+            let _ = tx.send(squashed.map(|(node, _)| node));
+        }
+        MultiverseCommand::Prune((handle, tx)) => {let _ = tx.send(multiverse.prune(&handle));}
+        MultiverseCommand::EditNode((handle, mut params, tx)) => {
+            let edited = multiverse.update_multiverse(handle, &mut params);
+            summary.nodes_edited += edited as usize;
+            let _ = tx.send(edited);
+        }
+        MultiverseCommand::TrackDiagnostics((handle, every, tx)) => {let _ = tx.send(diagnostics::track(multiverse, &handle, every));}
+        MultiverseCommand::TrackDivergence((a, b, options, tx)) => {
+            let _ = tx.send(divergence::track(multiverse, &a, &b, options));
+        }
+        MultiverseCommand::GetUniverseHash((handle, tx)) => {let _ = tx.send(multiverse.universe_hash(&handle));},
+        MultiverseCommand::GetRoots(tx) => {let _ = tx.send(multiverse.roots());}
+        MultiverseCommand::CreateRoot((scenario, tx)) => {
+            summary.nodes_created += 1;
+            let _ = tx.send(multiverse.create_root(&scenario));
+        }
+        // Only meaningful to start_multiverse, a second request has nothing left to do
+        MultiverseCommand::Shutdown => (),
+    };
 }
 
-pub fn start_multiverse(rx: Receiver<MultiverseCommand>) {
-    let mut multiverse = Multiverse::new();
-    loop {
-        let _ = match rx.recv() {
-            Ok(cmd) => 
-            {
-                match cmd {
-                    MultiverseCommand::AdvanceNode((handle, duration)) => {multiverse.advance(&handle, duration);},
-                    MultiverseCommand::GetUniverse((handle, tx)) => {tx.send(multiverse.get_universe(&handle));},
-                    MultiverseCommand::GetNodes(sender) => {sender.send(multiverse.get_nodes()).expect("Failed to send nodes");},
-                    MultiverseCommand::GetTimneline((handle, tx)) => {tx.send(multiverse.get_timeline(&handle));},
-                    MultiverseCommand::GetNode((handle, tx)) => {tx.send(multiverse.get_node(&handle));}
-                    MultiverseCommand::Branch((handle, params, duration)) => {multiverse.branch(&handle, duration, params);}
-                };
-            },
-            Err(_) => break,
-        };
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, sync::mpsc, thread};
+
+    use super::*;
+    use crate::scenario::TwoBody;
+
+    #[test]
+    fn shutdown_finishes_queued_commands_and_stores_them() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let physics = Physics::default();
+        let scenario = Scenario::TwoBody(TwoBody::default());
+        let seeded = Multiverse::open_seeded(&data_dir, physics, &scenario);
+        let root = seeded.root_node.unwrap();
+        seeded.close().unwrap();
+
+        // Everything is queued before the thread starts, including commands sent after the shutdown
+        let (tx, rx) = mpsc::channel();
+        let mut replies = Vec::new();
+        for duration in 1..=3 {
+            let (reply_tx, reply_rx) = mpsc::channel();
+            tx.send(MultiverseCommand::Branch((root, vec![], duration, reply_tx))).unwrap();
+            replies.push(reply_rx);
+        }
+        tx.send(MultiverseCommand::Shutdown).unwrap();
+        let (reply_tx, reply_rx) = mpsc::channel();
+        tx.send(MultiverseCommand::AdvanceNode((root, 4, reply_tx))).unwrap();
+        let dir = data_dir.clone();
+        let summary = thread::spawn(move || start_multiverse(rx, &dir, physics, &scenario)).join().unwrap();
+
+        let mut created: Vec<Handle> = replies.iter().map(|r| r.recv().unwrap().unwrap()).collect();
+        created.push(reply_rx.recv().unwrap().unwrap());
+        assert_eq!(summary.commands_processed, 4);
+        assert_eq!(summary.nodes_created, 4);
+        assert_eq!(summary.nodes_stored, 5);
+        assert!(summary.close_error.is_none());
+
+        let multiverse = Multiverse::open(&data_dir, physics);
+        assert_eq!(multiverse.get_nodes().len(), summary.nodes_stored);
+        for handle in created {
+            assert_eq!(multiverse.get_node(&handle).unwrap().parent, Some(root));
+        }
+        multiverse.close().unwrap();
+        fs::remove_dir_all(&data_dir).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,387 @@
+// Builds the OpenAPI 3 document describing the HTTP API.
+// Every schema comes from the schemars derives on the request and response types,
+// so the document stays in step with the types themselves. New routes need an entry in `document`.
+use schemars::{gen::{SchemaGenerator, SchemaSettings}, JsonSchema};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::{api::{AdvanceArgs, BranchArgs, EditArgs, EnsembleArgs, MultiverseInfo, RebaseArgs, SquashArgs, SweepArgs}, determinism::UniverseHash, diagnostics::{Diagnostics, DiagnosticsSample}, diff::UniverseDiff, divergence::DivergenceReport, ensemble::{EnsembleCreated, EnsembleStatistics}, events::Event, handle::Handle, multiverse::MultiverseNode, orbit::OrbitalElements, registry::CreateMultiverseArgs, scenario::Scenario, simulation::Universe, timeline::Timeline};
+
+pub struct OpenApi {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl OpenApi {
+    pub fn new() -> OpenApi {
+        OpenApi {
+            gen: SchemaSettings::openapi3().into_generator(),
+            paths: Map::new(),
+        }
+    }
+
+    // Returns a reference to T's schema, registering it under components/schemas
+    pub fn schema<T: JsonSchema>(&mut self) -> Value {
+        serde_json::to_value(self.gen.subschema_for::<T>()).expect("Failed to serialize schema")
+    }
+
+    pub fn path_param<T: JsonSchema>(&mut self, name: &str, description: &str) -> Value {
+        json!({
+            "name": name,
+            "in": "path",
+            "required": true,
+            "description": description,
+            "schema": self.schema::<T>(),
+        })
+    }
+
+    pub fn query_param<T: JsonSchema>(&mut self, name: &str, description: &str, required: bool) -> Value {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": required,
+            "description": description,
+            "schema": self.schema::<T>(),
+        })
+    }
+
+    pub fn route(&mut self, method: &str, path: &str, operation: Value) {
+        let entry = self.paths.entry(path).or_insert_with(|| Value::Object(Map::new()));
+        entry[method] = operation;
+    }
+
+    // Mounts a copy of every route documented so far under prefix/scope as well, with param added to each
+    pub fn scoped(&mut self, prefix: &str, scope: &str, param: Value) {
+        let copies: Vec<(String, Value)> = self.paths.iter()
+            .filter_map(|(path, item)| {
+                let rest = path.strip_prefix(prefix)?;
+                let mut item = item.clone();
+                for operation in item.as_object_mut()?.values_mut() {
+                    if let Some(params) = operation["parameters"].as_array_mut() {
+                        params.insert(0, param.clone());
+                    }
+                }
+                Some((format!("{}{}{}", prefix, scope, rest), item))
+            })
+            .collect();
+        self.paths.extend(copies);
+    }
+
+    // Adds a response to every operation documented so far under prefix, for failures any of them can hit
+    pub fn respond_everywhere(&mut self, prefix: &str, status: u16, description: &str) {
+        let operations = self.paths.iter_mut()
+            .filter(|(path, _)| path.starts_with(prefix))
+            .filter_map(|(_, item)| item.as_object_mut())
+            .flat_map(|item| item.values_mut());
+        for operation in operations {
+            operation["responses"][status.to_string()] = json!({ "description": description });
+        }
+    }
+
+    pub fn into_document(self) -> Value {
+        let schemas: Map<String, Value> = self.gen.definitions().iter()
+            .map(|(name, schema)| (name.clone(), serde_json::to_value(schema).expect("Failed to serialize schema")))
+            .collect();
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "Multiverse simulator",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": {
+                "schemas": schemas,
+            },
+        })
+    }
+}
+
+impl Default for OpenApi {
+    fn default() -> Self {
+        OpenApi::new()
+    }
+}
+
+// Responses are (status, description, optional JSON body schema)
+pub fn operation(summary: &str, params: Vec<Value>, body: Option<Value>, responses: Vec<(u16, &str, Option<Value>)>) -> Value {
+    let mut op = json!({
+        "summary": summary,
+        "parameters": params,
+        "responses": responses.into_iter().map(|(status, description, schema)| {
+            let mut response = json!({ "description": description });
+            if let Some(schema) = schema {
+                response["content"] = json!({ "application/json": { "schema": schema } });
+            }
+            (status.to_string(), response)
+        }).collect::<Map<String, Value>>(),
+    });
+    if let Some(schema) = body {
+        op["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": schema } },
+        });
+    }
+    op
+}
+
+pub fn document() -> Value {
+    let mut api = OpenApi::new();
+    let node_id = "Multiverse node handle";
+    let multiverse_id = "Multiverse id";
+
+    let nodes = api.schema::<Vec<Handle>>();
+    api.route("get", "/api/v1/nodes", operation("List every node in the multiverse", vec![], None, vec![
+        (200, "Node handles", Some(nodes)),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let node = api.schema::<MultiverseNode>();
+    api.route("get", "/api/v1/nodes/{uuid}", operation("Fetch a node", vec![param], None, vec![
+        (200, "The node", Some(node)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<EditArgs>();
+    api.route("patch", "/api/v1/nodes/{uuid}", operation("Append deltas to a node, invalidating its cached universes", vec![param], Some(body), vec![
+        (204, "Node updated", None),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let removed = api.schema::<Vec<Handle>>();
+    api.route("delete", "/api/v1/nodes/{uuid}", operation("Remove a node and everything descending from it", vec![param], None, vec![
+        (200, "Handles of the removed nodes", Some(removed)),
+        (404, "Node not found", None),
+        (409, "The root node can't be removed", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<AdvanceArgs>();
+    let created = api.schema::<Handle>();
+    api.route("post", "/api/v1/nodes/{uuid}/advance", operation("Create the canonical next node", vec![param], Some(body), vec![
+        (201, "Handle of the new node", Some(created)),
+        (404, "Node not found", None),
+        (409, "The node already has a next node", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<BranchArgs>();
+    let created = api.schema::<Handle>();
+    api.route("post", "/api/v1/nodes/{uuid}/branches", operation("Branch a new child node with deltas", vec![param], Some(body), vec![
+        (201, "Handle of the new node", Some(created)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<SweepArgs>();
+    let created = api.schema::<Vec<Handle>>();
+    api.route("post", "/api/v1/nodes/{uuid}/sweeps", operation("Branch one child node per point of a parameter grid", vec![param], Some(body), vec![
+        (201, "Handles of the new nodes, with the first axis varying slowest", Some(created)),
+        (400, "Invalid sweep", None),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<EnsembleArgs>();
+    let created = api.schema::<EnsembleCreated>();
+    api.route("post", "/api/v1/nodes/{uuid}/ensembles", operation("Branch a Monte Carlo ensemble of randomly perturbed children", vec![param], Some(body), vec![
+        (201, "The ensemble's id, seed and members", Some(created)),
+        (400, "Invalid ensemble, or a perturbation targets a body the node doesn't have", None),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("ensemble", "Ensemble id");
+    let age = api.query_param::<i32>("age", "Ticks since the members branched, defaulting to each member's own length", false);
+    let statistics = api.schema::<EnsembleStatistics>();
+    api.route("get", "/api/v1/ensembles/{ensemble}/statistics", operation("Mean and variance of each body's state across an ensemble", vec![param, age], None, vec![
+        (200, "Per-body statistics", Some(statistics)),
+        (400, "Invalid age", None),
+        (404, "Ensemble not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<RebaseArgs>();
+    let created = api.schema::<Handle>();
+    api.route("post", "/api/v1/nodes/{uuid}/rebase", operation("Re-apply a node's deltas as a new child of another node", vec![param], Some(body), vec![
+        (201, "Handle of the new node", Some(created)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let body = api.schema::<SquashArgs>();
+    let node = api.schema::<MultiverseNode>();
+    api.route("post", "/api/v1/nodes/{uuid}/squash", operation("Collapse the chain from a node down to one of its descendants into the node", vec![param], Some(body), vec![
+        (200, "The squashed node", Some(node)),
+        (404, "Node not found", None),
+        (409, "The chain can't be squashed without changing its universe or orphaning other branches", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let universe = api.schema::<Universe>();
+    api.route("get", "/api/v1/nodes/{uuid}/universe", operation("Fetch the universe at a node", vec![param], None, vec![
+        (200, "The universe", Some(universe)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let hash = api.schema::<UniverseHash>();
+    api.route("get", "/api/v1/nodes/{uuid}/universe/hash", operation("Content hash of the universe at a node. In deterministic mode, also the hashes of recomputes that didn't match it", vec![param], None, vec![
+        (200, "The hash", Some(hash)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let timeline = api.schema::<Timeline>();
+    api.route("get", "/api/v1/nodes/{uuid}/timeline", operation("Fetch the universes from the root down to a node", vec![param], None, vec![
+        (200, "The timeline", Some(timeline)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let diagnostics = api.schema::<Diagnostics>();
+    api.route("get", "/api/v1/nodes/{uuid}/universe/diagnostics", operation("Energy, momentum and center of mass of the universe at a node", vec![param], None, vec![
+        (200, "The diagnostics", Some(diagnostics)),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let every = api.query_param::<i32>("every", "Also sample every this many ticks within each node", false);
+    let samples = api.schema::<Vec<DiagnosticsSample>>();
+    api.route("get", "/api/v1/nodes/{uuid}/timeline/diagnostics", operation("Diagnostics sampled along the timeline from the root to a node", vec![param, every], None, vec![
+        (200, "Samples ordered by age", Some(samples)),
+        (400, "Invalid sampling interval", None),
+        (404, "Node not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let primary = api.query_param::<Uuid>("primary", "Body being orbited. Defaults to the secondary's dominant attractor", false);
+    let secondary = api.query_param::<Uuid>("secondary", "Orbiting body. Defaults to every body that has a dominant attractor", false);
+    let elements = api.schema::<Vec<OrbitalElements>>();
+    api.route("get", "/api/v1/nodes/{uuid}/universe/orbits", operation("Keplerian elements of bodies in the universe at a node", vec![param, primary, secondary], None, vec![
+        (200, "Elements of each requested orbit, leaving out degenerate ones such as radial or parabolic orbits", Some(elements)),
+        (400, "Invalid body selection", None),
+        (404, "Node or body not found", None),
+    ]));
+
+    let param = api.path_param::<Uuid>("uuid", node_id);
+    let kind = api.query_param::<String>("kind", "Only events of this kind: close_approach, escape, periapsis, apoapsis or collision", false);
+    let body = api.query_param::<Uuid>("body", "Only events involving this body", false);
+    let events = api.schema::<Vec<Event>>();
+    api.route("get", "/api/v1/nodes/{uuid}/universe/events", operation("Events the universe's detectors found during a node's ticks, in the order they happened", vec![param, kind, body], None, vec![
+        (200, "The events", Some(events)),
+        (404, "Node not found", None),
+    ]));
+
+    let a = api.query_param::<Uuid>("a", node_id, true);
+    let b = api.query_param::<Uuid>("b", node_id, true);
+    let diff = api.schema::<UniverseDiff>();
+    api.route("get", "/api/v1/diff", operation("Compare the universes at two nodes, body by body, as b - a", vec![a, b], None, vec![
+        (200, "The differences", Some(diff)),
+        (404, "Node not found", None),
+    ]));
+
+    let a = api.query_param::<Uuid>("a", node_id, true);
+    let b = api.query_param::<Uuid>("b", node_id, true);
+    let duration = api.query_param::<i32>("duration", "Ticks to replay past the common ancestor, defaulting to the longer branch, at most 1000000", false);
+    let every = api.query_param::<i32>("every", "Sample every this many ticks, defaulting to every tick", false);
+    let threshold = api.query_param::<f64>("threshold", "Fraction of the system's size at which the branches count as decorrelated, 0.5 by default", false);
+    let report = api.schema::<DivergenceReport>();
+    api.route("get", "/api/v1/divergence", operation("Replay two branches from their common ancestor, tracking their divergence and Lyapunov exponent", vec![a, b, duration, every, threshold], None, vec![
+        (200, "Divergence over time", Some(report)),
+        (400, "Invalid sampling parameters", None),
+        (404, "Node not found, or the nodes don't share a root", None),
+    ]));
+
+    let roots = api.schema::<Vec<Handle>>();
+    api.route("get", "/api/v1/roots", operation("List every root node, starting with the original root", vec![], None, vec![
+        (200, "Root node handles", Some(roots)),
+    ]));
+
+    let body = api.schema::<Scenario>();
+    let created = api.schema::<Handle>();
+    api.route("post", "/api/v1/roots", operation("Create a new root node from a scenario", vec![], Some(body), vec![
+        (201, "Handle of the new root", Some(created)),
+        (400, "Invalid scenario parameters", None),
+    ]));
+
+    // Everything above works on the default multiverse, and on any other under /api/v1/multiverses/{multiverse}
+    let param = api.path_param::<String>("multiverse", multiverse_id);
+    api.scoped("/api/v1", "/multiverses/{multiverse}", param);
+
+    let multiverses = api.schema::<Vec<MultiverseInfo>>();
+    api.route("get", "/api/v1/multiverses", operation("List every multiverse, starting with the default one", vec![], None, vec![
+        (200, "The multiverses", Some(multiverses)),
+    ]));
+
+    let body = api.schema::<CreateMultiverseArgs>();
+    let created = api.schema::<MultiverseInfo>();
+    api.route("post", "/api/v1/multiverses", operation("Create a multiverse with its own storage, seeded from a scenario", vec![], Some(body), vec![
+        (201, "The new multiverse", Some(created)),
+        (400, "Invalid id or scenario parameters", None),
+        (409, "A multiverse with that id already exists", None),
+    ]));
+
+    let param = api.path_param::<String>("multiverse", multiverse_id);
+    let info = api.schema::<MultiverseInfo>();
+    api.route("get", "/api/v1/multiverses/{multiverse}", operation("Fetch a multiverse", vec![param], None, vec![
+        (200, "The multiverse", Some(info)),
+        (404, "Multiverse not found", None),
+    ]));
+
+    let param = api.path_param::<String>("multiverse", multiverse_id);
+    api.route("delete", "/api/v1/multiverses/{multiverse}", operation("Stop a multiverse and delete its storage", vec![param], None, vec![
+        (204, "Multiverse deleted", None),
+        (400, "The default multiverse can't be deleted", None),
+        (404, "Multiverse not found", None),
+    ]));
+
+    // Every v1 route waits on a multiverse thread, which may have stopped
+    api.respond_everywhere("/api/v1", 503, "Multiverse is not running");
+
+    api.route("get", "/schema", operation("JSON Schema for branch requests", vec![], None, vec![
+        (200, "JSON Schema document", Some(json!({ "type": "object" }))),
+    ]));
+
+    api.route("get", "/api/openapi.json", operation("This document", vec![], None, vec![
+        (200, "OpenAPI 3 document", Some(json!({ "type": "object" }))),
+    ]));
+
+    api.into_document()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The routes the handlers declare, read from their attributes, with the scope they are mounted under.
+    // Only handlers that are registered as services count.
+    fn declared_routes(source: &str, prefix: &str) -> Vec<(String, String)> {
+        let lines: Vec<&str> = source.lines().collect();
+        lines.windows(2)
+            .filter_map(|pair| {
+                let attr = pair[0].trim().strip_prefix("#[")?;
+                let (method, rest) = attr.split_once("(\"")?;
+                let path = rest.strip_suffix("\")]")?;
+                let handler = pair[1].trim().strip_prefix("async fn ")?.split('(').next()?;
+                let registered = source.contains(&format!(".service({})", handler));
+                (registered && ["get", "post", "patch", "delete", "put"].contains(&method)).then(|| (method.to_string(), format!("{}{}", prefix, path)))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let document = document();
+        let v1 = declared_routes(include_str!("api.rs"), "/api/v1");
+        assert!(v1.len() > 20);
+        let scoped = v1.iter()
+            .filter(|(_, path)| !path.starts_with("/api/v1/multiverses"))
+            .map(|(method, path)| (method.clone(), path.replacen("/api/v1", "/api/v1/multiverses/{multiverse}", 1)));
+        for (method, path) in v1.clone().into_iter().chain(scoped) {
+            let operation = &document["paths"][&path][&method];
+            assert!(operation.is_object(), "{} {} isn't documented", method, path);
+            assert!(operation["responses"]["503"].is_object(), "{} {} doesn't document 503", method, path);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+// Keplerian orbital elements for pairs of bodies.
+// Angles are in radians and measured in the universe's own x/y plane, with +z as the reference pole.
+use std::f64::consts::PI;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::simulation::{Body, Pos, Universe};
+
+// Below this, eccentricity or inclination are treated as zero and the angles they define become degenerate
+const EPSILON: f64 = 1e-12;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OrbitalElements
+{
+    pub body: Uuid,
+    pub primary: Uuid,
+    // Negative for hyperbolic orbits
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    // Zero for orbits in the reference plane
+    pub longitude_of_ascending_node: f64,
+    // Measured from the ascending node, or from +x for orbits in the reference plane. Zero for circular orbits.
+    pub argument_of_periapsis: f64,
+    pub true_anomaly: f64,
+    // In the universe's time units, the same as its timestep. None for unbound orbits.
+    pub period: Option<f64>,
+}
+
+// Angle between a and b, flipped into (PI, 2PI) when `flip` is set
+fn angle(a: Pos, b: Pos, flip: bool) -> f64 {
+    let cos = (a.dot(b) / (a.length() * b.length())).clamp(-1.0, 1.0);
+    if flip { 2.0 * PI - cos.acos() } else { cos.acos() }
+}
+
+impl OrbitalElements {
+    // Elements of secondary's orbit around primary, treating them as an isolated two-body system.
+    // None when they have no elements: coincident or massless bodies, radial motion and parabolic orbits.
+    pub fn new(primary: &Body, secondary: &Body, g: f64) -> Option<OrbitalElements> {
+        let mu = g * (primary.gravitating_mass() + secondary.gravitating_mass());
+        let r = secondary.position - primary.position;
+        let v = secondary.velocity - primary.velocity;
+        let r_len = r.length();
+
+        let h = r.cross(v);
+        if mu <= 0.0 || r_len == 0.0 || h.length() <= EPSILON * r_len * v.length() {
+            return None;
+        }
+        let node = Pos{ x: -h.y, y: h.x, z: 0.0 };
+        let e_vec = (r * (v.dot(v) - mu / r_len) - v * r.dot(v)) * (1.0 / mu);
+        let eccentricity = e_vec.length();
+        let energy = v.dot(v) / 2.0 - mu / r_len;
+        let semi_major_axis = -mu / (2.0 * energy);
+        if !semi_major_axis.is_finite() {
+            return None;
+        }
+
+        let inclination = (h.z / h.length()).clamp(-1.0, 1.0).acos();
+        let equatorial = node.length() < EPSILON * h.length().max(1.0);
+        let circular = eccentricity < EPSILON;
+
+        let longitude_of_ascending_node = if equatorial { 0.0 } else { angle(Pos{ x: 1.0, y: 0.0, z: 0.0 }, node, node.y < 0.0) };
+        let argument_of_periapsis = match (circular, equatorial) {
+            (true, _) => 0.0,
+            (false, false) => angle(node, e_vec, e_vec.z < 0.0),
+            (false, true) => {
+                let w = e_vec.y.atan2(e_vec.x).rem_euclid(2.0 * PI);
+                if h.z < 0.0 { (2.0 * PI - w).rem_euclid(2.0 * PI) } else { w }
+            },
+        };
+        // For circular orbits there's no periapsis, so measure from the ascending node (or +x) instead
+        let true_anomaly = if circular {
+            let reference = if equatorial { Pos{ x: 1.0, y: 0.0, z: 0.0 } } else { node };
+            angle(reference, r, r.dot(h.cross(reference)) < 0.0)
+        } else {
+            angle(e_vec, r, r.dot(v) < 0.0)
+        };
+
+        Some(OrbitalElements {
+            body: secondary.id,
+            primary: primary.id,
+            semi_major_axis,
+            eccentricity,
+            inclination,
+            longitude_of_ascending_node,
+            argument_of_periapsis,
+            true_anomaly,
+            period: if energy < 0.0 { Some(2.0 * PI * (semi_major_axis.powi(3) / mu).sqrt()) } else { None },
+        })
+    }
+}
+
+// An orbit to place a body on, relative to a parent body. Only the semi-major axis is required,
+// everything else defaults to zero: a circular orbit in the reference plane starting on the +x side of the parent.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct OrbitSpec
+{
+    pub parent: Uuid,
+    // Negative for hyperbolic orbits
+    pub semi_major_axis: f64,
+    #[serde(default)]
+    pub eccentricity: f64,
+    #[serde(default)]
+    pub inclination: f64,
+    #[serde(default)]
+    pub longitude_of_ascending_node: f64,
+    #[serde(default)]
+    pub argument_of_periapsis: f64,
+    #[serde(default)]
+    pub true_anomaly: f64,
+}
+
+impl OrbitSpec {
+    // Position and velocity relative to the parent for a body of the given mass.
+    // None for parabolic orbits, or when the semi-major axis doesn't match the eccentricity.
+    pub fn state(&self, parent: &Body, mass: f64, g: f64) -> Option<(Pos, Pos)> {
+        let mu = g * (parent.gravitating_mass() + mass);
+        let e = self.eccentricity;
+        let a = self.semi_major_axis;
+        let bound = (0.0..1.0).contains(&e) && a > 0.0;
+        let unbound = e > 1.0 && a < 0.0;
+        if !(bound || unbound) || mu <= 0.0 {
+            return None;
+        }
+        let p = a * (1.0 - e * e);
+        let (sin_nu, cos_nu) = self.true_anomaly.sin_cos();
+        let denominator = 1.0 + e * cos_nu;
+        // Past the asymptotes of a hyperbola
+        if denominator <= 0.0 {
+            return None;
+        }
+        let r = p / denominator;
+        let speed = (mu / p).sqrt();
+        let position = Pos{ x: r * cos_nu, y: r * sin_nu, z: 0.0 };
+        let velocity = Pos{ x: -speed * sin_nu, y: speed * (e + cos_nu), z: 0.0 };
+        Some((self.rotate(position), self.rotate(velocity)))
+    }
+
+    // From the perifocal frame into the universe's frame
+    fn rotate(&self, v: Pos) -> Pos {
+        let (sin_o, cos_o) = self.longitude_of_ascending_node.sin_cos();
+        let (sin_i, cos_i) = self.inclination.sin_cos();
+        let (sin_w, cos_w) = self.argument_of_periapsis.sin_cos();
+        Pos{
+            x: (cos_o * cos_w - sin_o * sin_w * cos_i) * v.x + (-cos_o * sin_w - sin_o * cos_w * cos_i) * v.y,
+            y: (sin_o * cos_w + cos_o * sin_w * cos_i) * v.x + (-sin_o * sin_w + cos_o * cos_w * cos_i) * v.y,
+            z: (sin_w * sin_i) * v.x + (cos_w * sin_i) * v.y,
+        }
+    }
+}
+
+// Moves the body at index onto orbit around its parent, as things stand in the universe now
+pub fn place(universe: &mut Universe, index: usize, orbit: &OrbitSpec) -> Result<(), String> {
+    let body_id = universe.bodies[index].id;
+    let parent = match universe.get_body(orbit.parent) {
+        Some(parent) if parent.id != body_id => parent.clone(),
+        _ => return Err(format!("Orbit parent {} not found for body {}", orbit.parent, body_id)),
+    };
+    let g = universe.physics.gravitational_constant;
+    let body = &mut universe.bodies[index];
+    let (position, velocity) = orbit.state(&parent, body.gravitating_mass(), g).ok_or_else(|| format!("Invalid orbit for body {}", body_id))?;
+    body.position = parent.position + position;
+    body.velocity = parent.velocity + velocity;
+    Ok(())
+}
+
+// Assigns each body to the attractor whose sphere of influence it sits in. Bodies are placed heaviest first,
+// each starting at the heaviest body and descending into any lighter attractor whose Hill sphere contains it.
+// The heaviest body has no primary. Test particles count as massless, so they never attract anything.
+pub fn hierarchy(universe: &Universe) -> Vec<(Uuid, Option<Uuid>)> {
+    let mut order: Vec<&Body> = universe.bodies.iter().collect();
+    order.sort_by(|a, b| b.gravitating_mass().total_cmp(&a.gravitating_mass()));
+    // (body, primary, Hill radius)
+    let mut placed: Vec<(&Body, Option<usize>, f64)> = vec![];
+    for body in order {
+        let mut primary = None;
+        if !placed.is_empty() {
+            let mut current = 0;
+            loop {
+                let next = placed.iter().enumerate().find(|(_, (candidate, parent, hill))| {
+                    *parent == Some(current) && candidate.position.dist(body.position) < *hill
+                });
+                match next {
+                    Some((i, _)) => current = i,
+                    None => break,
+                }
+            }
+            primary = Some(current);
+        }
+        let hill = match primary {
+            Some(p) => {
+                let parent = placed[p].0;
+                let ratio = if parent.gravitating_mass() > 0.0 { body.gravitating_mass() / (3.0 * parent.gravitating_mass()) } else { 0.0 };
+                parent.position.dist(body.position) * ratio.cbrt()
+            },
+            None => f64::INFINITY,
+        };
+        placed.push((body, primary, hill));
+    }
+    placed.iter().map(|(body, primary, _)| (body.id, primary.map(|p| placed[p].0.id))).collect()
+}
+
+// Elements of every body around its primary from `hierarchy`, leaving out any that are degenerate
+pub fn all_elements(universe: &Universe) -> Vec<OrbitalElements> {
+    let g = universe.physics.gravitational_constant;
+    hierarchy(universe).into_iter().filter_map(|(body, primary)| {
+        let primary = universe.get_body(primary?)?;
+        OrbitalElements::new(primary, universe.get_body(body)?, g)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Physics;
+
+    fn body(mass: f64, position: Pos, velocity: Pos) -> Body {
+        Body { mass, position, velocity, ..Body::new() }
+    }
+
+    // Angles equal up to whole turns
+    fn same_angle(a: f64, b: f64) -> bool {
+        let d = (a - b).rem_euclid(2.0 * PI);
+        d.min(2.0 * PI - d) < 1e-9
+    }
+
+    #[test]
+    fn elements_survive_a_round_trip_through_state() {
+        let parent = body(1.0, Pos::default(), Pos::default());
+        let specs = [
+            OrbitSpec { parent: parent.id, semi_major_axis: 2.0, eccentricity: 0.3, inclination: 0.4, longitude_of_ascending_node: 1.0, argument_of_periapsis: 0.7, true_anomaly: 2.0 },
+            OrbitSpec { parent: parent.id, semi_major_axis: -1.5, eccentricity: 1.4, inclination: 2.5, longitude_of_ascending_node: 0.3, argument_of_periapsis: 4.0, true_anomaly: 0.5 },
+            OrbitSpec { parent: parent.id, semi_major_axis: 1.0, true_anomaly: 1.2, ..Default::default() },
+        ];
+        for spec in specs {
+            let (position, velocity) = spec.state(&parent, 1e-3, 1.0).expect("Spec should be valid");
+            let elements = OrbitalElements::new(&parent, &body(1e-3, position, velocity), 1.0).expect("Orbit shouldn't be degenerate");
+            assert!((elements.semi_major_axis - spec.semi_major_axis).abs() < 1e-9 * spec.semi_major_axis.abs());
+            assert!((elements.eccentricity - spec.eccentricity).abs() < 1e-9);
+            assert!(same_angle(elements.inclination, spec.inclination));
+            assert!(same_angle(elements.longitude_of_ascending_node, spec.longitude_of_ascending_node));
+            assert!(same_angle(elements.argument_of_periapsis, spec.argument_of_periapsis));
+            assert!(same_angle(elements.true_anomaly, spec.true_anomaly));
+            assert_eq!(elements.period.is_some(), spec.semi_major_axis > 0.0);
+        }
+    }
+
+    #[test]
+    fn placed_bodies_get_the_requested_elements() {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, ..Default::default() });
+        // A moving parent, so placing has to be relative to it
+        let parent = body(2.0, Pos { x: 5.0, y: -1.0, z: 0.5 }, Pos { x: 0.1, y: 0.2, z: 0.0 });
+        let parent_id = parent.id;
+        universe.add_body(parent);
+        universe.add_body(body(1e-3, Pos::default(), Pos::default()));
+        let spec = OrbitSpec { parent: parent_id, semi_major_axis: 3.0, eccentricity: 0.6, inclination: 0.2, longitude_of_ascending_node: 2.0, argument_of_periapsis: 1.0, true_anomaly: 3.0 };
+        place(&mut universe, 1, &spec).expect("Spec should be valid");
+
+        let elements = OrbitalElements::new(&universe.bodies[0], &universe.bodies[1], 1.0).expect("Orbit shouldn't be degenerate");
+        assert!((elements.semi_major_axis - 3.0).abs() < 1e-9);
+        assert!((elements.eccentricity - 0.6).abs() < 1e-9);
+        assert!(same_angle(elements.inclination, 0.2));
+        assert!(same_angle(elements.longitude_of_ascending_node, 2.0));
+        assert!(same_angle(elements.argument_of_periapsis, 1.0));
+        assert!(same_angle(elements.true_anomaly, 3.0));
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let parent = body(1.0, Pos::default(), Pos::default());
+        let parent_id = parent.id;
+        let spec = |semi_major_axis: f64, eccentricity: f64, true_anomaly: f64| OrbitSpec { parent: parent_id, semi_major_axis, eccentricity, true_anomaly, ..Default::default() };
+        for invalid in [
+            // Elliptical eccentricity needs a positive semi-major axis, and hyperbolic a negative one
+            spec(-1.0, 0.5, 0.0),
+            spec(0.0, 0.0, 0.0),
+            spec(1.0, 1.5, 0.0),
+            // Parabolic and negative eccentricities aren't supported
+            spec(1.0, 1.0, 0.0),
+            spec(1.0, -0.1, 0.0),
+            // Beyond the hyperbola's asymptotes, at most acos(-1/e) from periapsis
+            spec(-1.0, 2.0, 2.5),
+        ] {
+            assert!(invalid.state(&parent, 0.0, 1.0).is_none());
+        }
+        // Only a massive parent can hold a body in orbit
+        assert!(spec(1.0, 0.0, 0.0).state(&body(0.0, Pos::default(), Pos::default()), 0.0, 1.0).is_none());
+
+        // place refuses a missing parent, a body as its own parent and an invalid spec, leaving the body where it was
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, ..Default::default() });
+        universe.add_body(parent);
+        let start = Pos { x: 7.0, y: 0.0, z: 0.0 };
+        universe.add_body(body(1e-3, start, Pos::default()));
+        let own_id = universe.bodies[1].id;
+        for orbit in [
+            OrbitSpec { parent: Uuid::new_v4(), ..spec(1.0, 0.0, 0.0) },
+            OrbitSpec { parent: own_id, ..spec(1.0, 0.0, 0.0) },
+            OrbitSpec { parent: parent_id, ..spec(1.0, 1.5, 0.0) },
+        ] {
+            assert!(place(&mut universe, 1, &orbit).is_err());
+            assert_eq!(universe.bodies[1].position.x, start.x);
+        }
+    }
+
+    #[test]
+    fn degenerate_orbits_have_no_elements() {
+        let parent = body(1.0, Pos::default(), Pos::default());
+        let at = Pos { x: 1.0, y: 0.0, z: 0.0 };
+        // Falling straight in
+        assert!(OrbitalElements::new(&parent, &body(1e-3, at, Pos { x: -0.5, y: 0.0, z: 0.0 }), 1.0).is_none());
+        // At rest
+        assert!(OrbitalElements::new(&parent, &body(1e-3, at, Pos::default()), 1.0).is_none());
+        assert!(OrbitalElements::new(&parent, &body(1e-3, Pos::default(), Pos { x: 0.0, y: 1.0, z: 0.0 }), 1.0).is_none());
+        // Nothing to orbit
+        let massless = body(0.0, Pos::default(), Pos::default());
+        assert!(OrbitalElements::new(&massless, &body(0.0, at, Pos { x: 0.0, y: 1.0, z: 0.0 }), 1.0).is_none());
+    }
+}
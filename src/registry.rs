@@ -0,0 +1,210 @@
+// Keeps track of every multiverse the server is running. Each one gets its own thread and its own
+// data directory, so experiments never share a tree. The default multiverse lives directly in the
+// configured data directory, where a single-multiverse server has always kept it, and every other
+// one lives in its own subdirectory of MULTIVERSES_DIR.
+use std::{collections::HashMap, fmt, fs, path::{Path, PathBuf}, sync::{mpsc::{self, Sender}, Mutex}, thread::{self, JoinHandle}};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{config::Config, multiverse_manager::{self, MultiverseCommand, ShutdownSummary}, scenario::Scenario};
+
+pub const DEFAULT_MULTIVERSE: &str = "default";
+pub const MULTIVERSES_DIR: &str = "multiverses";
+
+#[derive(Debug)]
+pub enum RegistryError {
+    NotFound,
+    AlreadyExists,
+    InvalidId,
+    InvalidScenario(String),
+    DefaultIsPermanent,
+    Storage(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::NotFound => write!(f, "Multiverse not found"),
+            RegistryError::AlreadyExists => write!(f, "A multiverse with that id already exists"),
+            RegistryError::InvalidId => write!(f, "Multiverse ids may only contain letters, digits, '-' and '_'"),
+            RegistryError::InvalidScenario(e) => write!(f, "Invalid scenario: {}", e),
+            RegistryError::DefaultIsPermanent => write!(f, "The default multiverse can't be deleted"),
+            RegistryError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+pub struct CreateMultiverseArgs {
+    // Defaults to a random UUID
+    pub id: Option<String>,
+    // Scenario for the new multiverse's root. Defaults to the configured scenario.
+    pub scenario: Option<Scenario>,
+}
+
+struct Running {
+    chan: Sender<MultiverseCommand>,
+    thread: JoinHandle<ShutdownSummary>,
+    data_dir: PathBuf,
+}
+
+pub struct Registry {
+    config: Config,
+    multiverses: Mutex<HashMap<String, Running>>,
+}
+
+// Where a multiverse other than the default one keeps its databases
+pub fn multiverse_dir(data_dir: &Path, id: &str) -> PathBuf {
+    if id == DEFAULT_MULTIVERSE {
+        data_dir.to_path_buf()
+    } else {
+        data_dir.join(MULTIVERSES_DIR).join(id)
+    }
+}
+
+// Ids become directory names, so keep them to characters that are safe in any path
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn spawn(data_dir: PathBuf, config: &Config, scenario: Scenario) -> Running {
+    let (tx, rx) = mpsc::channel();
+    let dir = data_dir.clone();
+    let physics = config.physics;
+    let thread = thread::spawn(move || {
+        multiverse_manager::start_multiverse(rx, &dir, physics, &scenario)
+    });
+    Running { chan: tx, thread, data_dir }
+}
+
+impl Registry {
+    // Starts the default multiverse and every multiverse left in the data directory by a previous run
+    pub fn open(config: Config) -> Registry {
+        let mut multiverses = HashMap::new();
+        multiverses.insert(String::from(DEFAULT_MULTIVERSE), spawn(config.data_dir.clone(), &config, config.scenario.clone()));
+        if let Ok(entries) = fs::read_dir(config.data_dir.join(MULTIVERSES_DIR)) {
+            for entry in entries.flatten() {
+                let id = entry.file_name().to_string_lossy().into_owned();
+                if entry.path().is_dir() && valid_id(&id) && id != DEFAULT_MULTIVERSE {
+                    log::info!("Resuming multiverse {}", id);
+                    multiverses.insert(id, spawn(entry.path(), &config, config.scenario.clone()));
+                }
+            }
+        }
+        Registry { config, multiverses: Mutex::new(multiverses) }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Sender<MultiverseCommand>> {
+        self.multiverses.lock().expect("Multiverse registry poisoned").get(id).map(|m| m.chan.clone())
+    }
+
+    // The default multiverse first, then the rest by id
+    pub fn list(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.multiverses.lock().expect("Multiverse registry poisoned").keys()
+            .filter(|id| *id != DEFAULT_MULTIVERSE)
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.insert(0, String::from(DEFAULT_MULTIVERSE));
+        ids
+    }
+
+    pub fn create(&self, args: CreateMultiverseArgs) -> Result<String, RegistryError> {
+        let id = args.id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        if !valid_id(&id) {
+            return Err(RegistryError::InvalidId);
+        }
+        if let Some(e) = args.scenario.as_ref().and_then(|s| s.validate().err()) {
+            return Err(RegistryError::InvalidScenario(e));
+        }
+        let mut multiverses = self.multiverses.lock().expect("Multiverse registry poisoned");
+        let data_dir = multiverse_dir(&self.config.data_dir, &id);
+        if multiverses.contains_key(&id) || data_dir.exists() {
+            return Err(RegistryError::AlreadyExists);
+        }
+        fs::create_dir_all(&data_dir).map_err(|e| RegistryError::Storage(format!("Failed to create {}: {}", data_dir.display(), e)))?;
+        let scenario = args.scenario.unwrap_or_else(|| self.config.scenario.clone());
+        multiverses.insert(id.clone(), spawn(data_dir, &self.config, scenario));
+        Ok(id)
+    }
+
+    // Stops the multiverse and deletes its storage
+    pub fn delete(&self, id: &str) -> Result<ShutdownSummary, RegistryError> {
+        if id == DEFAULT_MULTIVERSE {
+            return Err(RegistryError::DefaultIsPermanent);
+        }
+        let running = self.multiverses.lock().expect("Multiverse registry poisoned").remove(id).ok_or(RegistryError::NotFound)?;
+        let summary = stop(running.chan, running.thread)?;
+        fs::remove_dir_all(&running.data_dir)
+            .map_err(|e| RegistryError::Storage(format!("Failed to remove {}: {}", running.data_dir.display(), e)))?;
+        Ok(summary)
+    }
+
+    // Stops every multiverse, letting each finish the commands it already has queued
+    pub fn shutdown(&self) -> Vec<(String, Result<ShutdownSummary, RegistryError>)> {
+        let running: Vec<(String, Running)> = self.multiverses.lock().expect("Multiverse registry poisoned").drain().collect();
+        let mut summaries: Vec<_> = running.into_iter()
+            .map(|(id, m)| (id, stop(m.chan, m.thread)))
+            .collect();
+        summaries.sort_by(|a, b| (a.0 != DEFAULT_MULTIVERSE, &a.0).cmp(&(b.0 != DEFAULT_MULTIVERSE, &b.0)));
+        summaries
+    }
+}
+
+fn stop(chan: Sender<MultiverseCommand>, thread: JoinHandle<ShutdownSummary>) -> Result<ShutdownSummary, RegistryError> {
+    let _ = chan.send(MultiverseCommand::Shutdown);
+    thread.join().map_err(|_| RegistryError::Storage(String::from("Multiverse thread panicked, some changes may not have been persisted")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::scenario::TwoBody;
+
+    #[test]
+    fn ids_must_be_safe_directory_names() {
+        assert!(valid_id("experiment-1_b"));
+        for id in ["", "../escape", "a/b", "with space", &"x".repeat(65)] {
+            assert!(!valid_id(id), "{:?}", id);
+        }
+        let data_dir = Path::new("data");
+        assert_eq!(multiverse_dir(data_dir, DEFAULT_MULTIVERSE), data_dir);
+        assert_eq!(multiverse_dir(data_dir, "other"), data_dir.join(MULTIVERSES_DIR).join("other"));
+    }
+
+    #[test]
+    fn multiverses_are_created_deleted_and_resumed() {
+        let data_dir = env::temp_dir().join(format!("multiverse-test-{}", Uuid::new_v4()));
+        let config = Config { data_dir: data_dir.clone(), ..Default::default() };
+        let registry = Registry::open(config.clone());
+        let create = |id: &str, scenario: Option<Scenario>| registry.create(CreateMultiverseArgs { id: Some(String::from(id)), scenario });
+
+        assert_eq!(create("kept", None).expect("Should create"), "kept");
+        assert_eq!(create("doomed", Some(Scenario::TwoBody(TwoBody::default()))).expect("Should create"), "doomed");
+        assert_eq!(registry.list(), ["default", "doomed", "kept"]);
+        assert!(matches!(create("kept", None), Err(RegistryError::AlreadyExists)));
+        assert!(matches!(create("../kept", None), Err(RegistryError::InvalidId)));
+        let invalid = Scenario::TwoBody(TwoBody { eccentricity: 1.5, ..Default::default() });
+        assert!(matches!(create("invalid", Some(invalid)), Err(RegistryError::InvalidScenario(_))));
+        assert!(!multiverse_dir(&data_dir, "invalid").exists());
+
+        assert!(matches!(registry.delete(DEFAULT_MULTIVERSE), Err(RegistryError::DefaultIsPermanent)));
+        registry.delete("doomed").expect("Should delete");
+        assert!(!multiverse_dir(&data_dir, "doomed").exists());
+        assert!(matches!(registry.delete("doomed"), Err(RegistryError::NotFound)));
+
+        let summaries = registry.shutdown();
+        assert_eq!(summaries.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(), ["default", "kept"]);
+        assert!(summaries.iter().all(|(_, summary)| summary.as_ref().is_ok_and(|s| s.close_error.is_none())));
+
+        // A restart picks up the multiverse left on disk
+        let registry = Registry::open(config);
+        assert_eq!(registry.list(), ["default", "kept"]);
+        registry.shutdown();
+        fs::remove_dir_all(&data_dir).expect("Failed to remove test data");
+    }
+}
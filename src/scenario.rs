@@ -0,0 +1,429 @@
+// Named starting configurations for new root universes.
+// Every scenario is built against the gravitational constant of the multiverse it's seeding,
+// so its orbits are bound and its velocities sensible whatever units the physics is in.
+use std::f64::consts::PI;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{delta::{Delta, Operation}, orbit::OrbitSpec, simulation::{Body, Pos}};
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum Scenario {
+    SingleBody(SingleBody),
+    TwoBody(TwoBody),
+    FigureEight(FigureEight),
+    // The Sun, Mercury, Venus, Earth and Mars at J2000, in SI units
+    InnerSolarSystem,
+    PlummerSphere(PlummerSphere),
+    RandomCluster(RandomCluster),
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario::SingleBody(SingleBody::default())
+    }
+}
+
+// One body drifting on its own, which is what new multiverses have always started with
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct SingleBody {
+    pub mass: f64,
+    pub position: Pos,
+    pub velocity: Pos,
+}
+
+impl Default for SingleBody {
+    fn default() -> Self {
+        SingleBody {
+            mass: 1.0,
+            position: Pos{ x: 1.0, y: 2.0, z: 0.0 },
+            velocity: Pos{ x: 0.0, y: 0.0, z: 0.1 },
+        }
+    }
+}
+
+// A secondary orbiting a primary, starting at periapsis in the x/y plane, around their barycenter
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoBody {
+    pub primary_mass: f64,
+    pub secondary_mass: f64,
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+}
+
+impl Default for TwoBody {
+    fn default() -> Self {
+        TwoBody {
+            primary_mass: 1.0,
+            secondary_mass: 1e-3,
+            semi_major_axis: 1.0,
+            eccentricity: 0.0,
+        }
+    }
+}
+
+// Chenciner and Montgomery's periodic three-body orbit, three equal masses chasing each other around a figure eight
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct FigureEight {
+    pub mass: f64,
+    // Half the width of the figure eight, roughly
+    pub scale: f64,
+}
+
+impl Default for FigureEight {
+    fn default() -> Self {
+        FigureEight { mass: 1.0, scale: 1.0 }
+    }
+}
+
+// A spherical cluster in equilibrium, sampled as described by Aarseth, Hénon and Wielen (1974)
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlummerSphere {
+    pub count: usize,
+    pub total_mass: f64,
+    pub scale_radius: f64,
+    pub seed: u64,
+}
+
+impl Default for PlummerSphere {
+    fn default() -> Self {
+        PlummerSphere { count: 100, total_mass: 1.0, scale_radius: 1.0, seed: 0 }
+    }
+}
+
+// Bodies placed uniformly in a ball with uniformly random masses and velocities
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct RandomCluster {
+    pub count: usize,
+    pub radius: f64,
+    pub min_mass: f64,
+    pub max_mass: f64,
+    // Zero starts every body at rest
+    pub max_speed: f64,
+    pub seed: u64,
+}
+
+impl Default for RandomCluster {
+    fn default() -> Self {
+        RandomCluster { count: 50, radius: 1.0, min_mass: 0.01, max_mass: 0.1, max_speed: 0.0, seed: 0 }
+    }
+}
+
+// (mass, position, velocity)
+type State = (f64, Pos, Pos);
+
+impl Scenario {
+    // Deltas that build the scenario from an empty universe
+    pub fn deltas(&self, g: f64) -> Vec<Delta> {
+        let mut bodies = match self {
+            Scenario::SingleBody(s) => return vec![new_body((s.mass, s.position, s.velocity), None)],
+            Scenario::TwoBody(s) => two_body(s, g),
+            Scenario::FigureEight(s) => figure_eight(s, g),
+            Scenario::InnerSolarSystem => inner_solar_system(g),
+            Scenario::PlummerSphere(s) => plummer_sphere(s, g),
+            Scenario::RandomCluster(s) => random_cluster(s),
+        };
+        to_barycentric(&mut bodies);
+        let names: &[&str] = match self {
+            Scenario::InnerSolarSystem => &INNER_SOLAR_SYSTEM,
+            _ => &[],
+        };
+        bodies.into_iter().enumerate().map(|(i, body)| new_body(body, names.get(i).copied())).collect()
+    }
+
+    // Catches parameters that would otherwise build a universe other than the one asked for
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Scenario::SingleBody(s) => {
+                non_negative("mass", s.mass)?;
+                let (p, v) = (s.position, s.velocity);
+                if ![p.x, p.y, p.z, v.x, v.y, v.z].iter().all(|c| c.is_finite()) {
+                    return Err(String::from("position and velocity must be finite"));
+                }
+            }
+            Scenario::TwoBody(s) => {
+                positive("primary_mass", s.primary_mass)?;
+                non_negative("secondary_mass", s.secondary_mass)?;
+                let orbit = OrbitSpec { semi_major_axis: s.semi_major_axis, eccentricity: s.eccentricity, ..Default::default() };
+                let primary = Body { mass: s.primary_mass, ..Default::default() };
+                if orbit.state(&primary, s.secondary_mass, 1.0).is_none() {
+                    return Err(format!(
+                        "semi_major_axis {} and eccentricity {} don't describe an orbit: it needs a > 0 and 0 <= e < 1, or a < 0 and e > 1",
+                        s.semi_major_axis, s.eccentricity));
+                }
+            }
+            Scenario::FigureEight(s) => {
+                positive("mass", s.mass)?;
+                positive("scale", s.scale)?;
+            }
+            Scenario::InnerSolarSystem => {}
+            Scenario::PlummerSphere(s) => {
+                if s.count == 0 {
+                    return Err(String::from("count must be at least 1"));
+                }
+                positive("total_mass", s.total_mass)?;
+                positive("scale_radius", s.scale_radius)?;
+            }
+            Scenario::RandomCluster(s) => {
+                if s.count == 0 {
+                    return Err(String::from("count must be at least 1"));
+                }
+                non_negative("radius", s.radius)?;
+                non_negative("min_mass", s.min_mass)?;
+                non_negative("max_mass", s.max_mass)?;
+                non_negative("max_speed", s.max_speed)?;
+                if s.min_mass > s.max_mass {
+                    return Err(format!("min_mass {} is more than max_mass {}", s.min_mass, s.max_mass));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn positive(name: &str, value: f64) -> Result<(), String> {
+    if !(value.is_finite() && value > 0.0) {
+        return Err(format!("{} must be a positive number, got {}", name, value));
+    }
+    Ok(())
+}
+
+fn non_negative(name: &str, value: f64) -> Result<(), String> {
+    if !(value.is_finite() && value >= 0.0) {
+        return Err(format!("{} must be a non-negative number, got {}", name, value));
+    }
+    Ok(())
+}
+
+// The root node gives each body its id when it's created, so it's the same every time the root's universe is rebuilt
+fn new_body((mass, position, velocity): State, name: Option<&str>) -> Delta {
+    Delta::Op(Operation::AddBody {
+        id: None,
+        mass,
+        position,
+        velocity,
+        orbit: None,
+        name: name.map(String::from),
+        color: None,
+        radius: None,
+        properties: Default::default(),
+        motion: Default::default(),
+        test_particle: false,
+    })
+}
+
+// Shifts everything so the center of mass sits still at the origin
+fn to_barycentric(bodies: &mut [State]) {
+    let total: f64 = bodies.iter().map(|b| b.0).sum();
+    if total == 0.0 {
+        return;
+    }
+    let center: Pos = bodies.iter().map(|b| b.1 * b.0).sum::<Pos>() * (1.0 / total);
+    let drift: Pos = bodies.iter().map(|b| b.2 * b.0).sum::<Pos>() * (1.0 / total);
+    for body in bodies.iter_mut() {
+        body.1 = body.1 - center;
+        body.2 = body.2 - drift;
+    }
+}
+
+// Relative state of a body of `mass` on `orbit` around a body of `parent_mass` sitting at the origin.
+// Scenarios are validated before they're built, so this only falls back to the parent's state when G isn't positive.
+fn orbit_state(orbit: OrbitSpec, parent_mass: f64, mass: f64, g: f64) -> (Pos, Pos) {
+    let parent = Body { mass: parent_mass, ..Default::default() };
+    orbit.state(&parent, mass, g).unwrap_or_default()
+}
+
+fn two_body(s: &TwoBody, g: f64) -> Vec<State> {
+    let orbit = OrbitSpec { semi_major_axis: s.semi_major_axis, eccentricity: s.eccentricity, ..Default::default() };
+    let (position, velocity) = orbit_state(orbit, s.primary_mass, s.secondary_mass, g);
+    vec![
+        (s.primary_mass, Pos::default(), Pos::default()),
+        (s.secondary_mass, position, velocity),
+    ]
+}
+
+fn figure_eight(s: &FigureEight, g: f64) -> Vec<State> {
+    // Initial conditions for G = m = 1, rescaled to the requested mass and size
+    let speed = (g * s.mass / s.scale).sqrt();
+    let p = Pos{ x: 0.97000436, y: -0.24308753, z: 0.0 };
+    let v = Pos{ x: -0.93240737, y: -0.86473146, z: 0.0 };
+    vec![
+        (s.mass, p * s.scale, v * (-0.5 * speed)),
+        (s.mass, p * -s.scale, v * (-0.5 * speed)),
+        (s.mass, Pos::default(), v * speed),
+    ]
+}
+
+// Solves Kepler's equation for the true anomaly at mean anomaly m
+fn true_anomaly(m: f64, e: f64) -> f64 {
+    let mut big_e = m;
+    for _ in 0..50 {
+        let step = (big_e - e * big_e.sin() - m) / (1.0 - e * big_e.cos());
+        big_e -= step;
+        if step.abs() < 1e-15 {
+            break;
+        }
+    }
+    2.0 * ((1.0 + e).sqrt() * (big_e / 2.0).sin()).atan2((1.0 - e).sqrt() * (big_e / 2.0).cos())
+}
+
+// In the order inner_solar_system builds them
+const INNER_SOLAR_SYSTEM: [&str; 5] = ["Sun", "Mercury", "Venus", "Earth", "Mars"];
+
+fn inner_solar_system(g: f64) -> Vec<State> {
+    const AU: f64 = 1.495978707e11;
+    const SUN_MASS: f64 = 1.98892e30;
+    // mass (kg), a (AU), e, i, longitude of ascending node, argument of periapsis, mean anomaly (degrees)
+    const PLANETS: [(f64, f64, f64, f64, f64, f64, f64); 4] = [
+        (3.3011e23, 0.387098, 0.205630, 7.005, 48.331, 29.124, 174.796),
+        (4.8675e24, 0.723332, 0.006772, 3.39458, 76.680, 54.884, 50.115),
+        (5.97237e24, 1.000001, 0.016709, 0.00005, -11.26064, 114.20783, 358.617),
+        (6.4171e23, 1.523680, 0.093394, 1.850, 49.558, 286.502, 19.412),
+    ];
+    let mut bodies = vec![(SUN_MASS, Pos::default(), Pos::default())];
+    for (mass, a, e, i, node, peri, mean) in PLANETS {
+        let orbit = OrbitSpec {
+            semi_major_axis: a * AU,
+            eccentricity: e,
+            inclination: i.to_radians(),
+            longitude_of_ascending_node: node.to_radians(),
+            argument_of_periapsis: peri.to_radians(),
+            true_anomaly: true_anomaly(mean.to_radians(), e),
+            ..Default::default()
+        };
+        let (position, velocity) = orbit_state(orbit, SUN_MASS, mass, g);
+        bodies.push((mass, position, velocity));
+    }
+    bodies
+}
+
+// A uniformly random direction scaled to length
+fn isotropic(rng: &mut ChaCha8Rng, length: f64) -> Pos {
+    let z: f64 = rng.random_range(-1.0..=1.0);
+    let phi: f64 = rng.random_range(0.0..2.0 * PI);
+    let r = (1.0 - z * z).sqrt();
+    Pos{ x: r * phi.cos(), y: r * phi.sin(), z } * length
+}
+
+fn plummer_sphere(s: &PlummerSphere, g: f64) -> Vec<State> {
+    let mut rng = ChaCha8Rng::seed_from_u64(s.seed);
+    let mass = s.total_mass / s.count.max(1) as f64;
+    let a = s.scale_radius;
+    (0..s.count).map(|_| {
+        // Skip the far tail of the distribution, which would otherwise throw the odd body out to huge radii
+        let r = loop {
+            let x: f64 = rng.random_range(f64::EPSILON..1.0);
+            let r = a / (x.powf(-2.0 / 3.0) - 1.0).sqrt();
+            if r < 10.0 * a {
+                break r;
+            }
+        };
+        // Von Neumann rejection on q^2 (1 - q^2)^(7/2), whose maximum is just under 0.1
+        let q = loop {
+            let q: f64 = rng.random_range(0.0..1.0);
+            let y: f64 = rng.random_range(0.0..0.1);
+            if y < q * q * (1.0 - q * q).powf(3.5) {
+                break q;
+            }
+        };
+        let escape_speed = (2.0 * g * s.total_mass).sqrt() * (r * r + a * a).powf(-0.25);
+        let position = isotropic(&mut rng, r);
+        let velocity = isotropic(&mut rng, q * escape_speed);
+        (mass, position, velocity)
+    }).collect()
+}
+
+fn random_cluster(s: &RandomCluster) -> Vec<State> {
+    let mut rng = ChaCha8Rng::seed_from_u64(s.seed);
+    (0..s.count).map(|_| {
+        let mass = if s.max_mass > s.min_mass { rng.random_range(s.min_mass..s.max_mass) } else { s.min_mass };
+        let r = s.radius * rng.random_range(0.0f64..=1.0).cbrt();
+        let position = isotropic(&mut rng, r);
+        let speed = s.max_speed * rng.random_range(0.0f64..=1.0).cbrt();
+        let velocity = isotropic(&mut rng, speed);
+        (mass, position, velocity)
+    }).collect()
+}
+
+// Builds a scenario from its name and KEY=VALUE parameters, as given on the command line.
+// Values are read as JSON where possible, so numbers and vectors like {"x":1,"y":0,"z":0} work.
+pub fn from_args(name: &str, params: &[String]) -> Result<Scenario, String> {
+    let mut fields = serde_json::Map::new();
+    fields.insert(String::from("name"), serde_json::Value::String(name.to_string()));
+    for param in params {
+        let (key, value) = param.split_once('=').ok_or_else(|| format!("Expected KEY=VALUE, got {}", param))?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        fields.insert(key.to_string(), value);
+    }
+    serde_json::from_value(serde_json::Value::Object(fields)).map_err(|e| format!("Invalid scenario: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diagnostics::Diagnostics, simulation::{Physics, Universe}};
+
+    fn build(scenario: &Scenario, g: f64) -> Universe {
+        let mut universe = Universe::with_physics(Physics { gravitational_constant: g, ..Default::default() });
+        for delta in scenario.deltas(g) {
+            delta.apply_universe(&mut universe);
+        }
+        universe
+    }
+
+    #[test]
+    fn scenarios_build_their_bodies_at_rest_about_the_barycenter() {
+        let scenarios = [
+            (Scenario::TwoBody(TwoBody::default()), 1.0, 2),
+            (Scenario::TwoBody(TwoBody { semi_major_axis: -2.0, eccentricity: 1.5, ..Default::default() }), 1.0, 2),
+            (Scenario::FigureEight(FigureEight::default()), 1.0, 3),
+            (Scenario::InnerSolarSystem, 6.674e-11, 5),
+            (Scenario::PlummerSphere(PlummerSphere { count: 40, ..Default::default() }), 1.0, 40),
+            (Scenario::RandomCluster(RandomCluster { count: 30, max_speed: 0.5, ..Default::default() }), 1.0, 30),
+        ];
+        for (scenario, g, count) in scenarios {
+            scenario.validate().unwrap();
+            let universe = build(&scenario, g);
+            assert_eq!(universe.bodies.len(), count, "{:?}", scenario);
+            let diagnostics = Diagnostics::new(&universe);
+            let scale: f64 = universe.bodies.iter().map(|b| b.mass * b.velocity.length()).sum();
+            assert!(diagnostics.linear_momentum.length() <= 1e-12 * scale, "{:?}", scenario);
+            assert!(diagnostics.center_of_mass.length() <= 1e-9, "{:?}", scenario);
+        }
+    }
+
+    #[test]
+    fn a_single_body_is_left_where_it_was_put() {
+        let scenario = Scenario::default();
+        let universe = build(&scenario, 1.0);
+        assert_eq!(universe.bodies.len(), 1);
+        assert!((Diagnostics::new(&universe).linear_momentum - SingleBody::default().velocity).is_zero());
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let invalid = [
+            Scenario::SingleBody(SingleBody { mass: -1.0, ..Default::default() }),
+            Scenario::SingleBody(SingleBody { velocity: Pos { x: f64::NAN, y: 0.0, z: 0.0 }, ..Default::default() }),
+            Scenario::TwoBody(TwoBody { eccentricity: 1.5, ..Default::default() }),
+            Scenario::TwoBody(TwoBody { semi_major_axis: 0.0, ..Default::default() }),
+            Scenario::TwoBody(TwoBody { primary_mass: 0.0, ..Default::default() }),
+            Scenario::FigureEight(FigureEight { scale: 0.0, ..Default::default() }),
+            Scenario::PlummerSphere(PlummerSphere { count: 0, ..Default::default() }),
+            Scenario::RandomCluster(RandomCluster { min_mass: 0.2, max_mass: 0.1, ..Default::default() }),
+            Scenario::RandomCluster(RandomCluster { radius: f64::INFINITY, ..Default::default() }),
+        ];
+        for scenario in invalid {
+            assert!(scenario.validate().is_err(), "{:?}", scenario);
+        }
+    }
+}
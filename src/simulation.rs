@@ -1,4 +1,4 @@
-use std::{default, iter, ops, vec};
+use std::{collections::BTreeMap, iter, ops, vec};
 
 use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
@@ -6,7 +6,7 @@ use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 use physical_constants::{self, NEWTONIAN_CONSTANT_OF_GRAVITATION};
 
-use crate::handle::Handle;
+use crate::{diff::UniverseDiff, events::{self, Detector, Event}, forces::{self, Force}, integrator::{self, Integrator}, motion::Motion};
 
 #[derive(Default, Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
 pub struct Pos
@@ -18,13 +18,54 @@ pub struct Pos
 
 impl Pos {
 
-    fn dist(&self, other: Pos) -> f64 {
+    pub fn dist(&self, other: Pos) -> f64 {
         self.dist_sq(other).sqrt()
     }
 
-    fn dist_sq(&self, other: Pos) -> f64 {
+    pub fn dist_sq(&self, other: Pos) -> f64 {
         (self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2)
     }
+
+    pub fn dot(&self, other: Pos) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(&self, other: Pos) -> Pos {
+        Pos{
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(&self) -> f64 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0 && self.z == 0.0
+    }
+
+    // Kahan summation: adds value, keeping the low-order bits that don't fit in self in compensation,
+    // which has to be passed back in with the next addition to the same sum
+    pub fn add_compensated(&mut self, value: Pos, compensation: &mut Pos) {
+        for (sum, c, v) in [(&mut self.x, &mut compensation.x, value.x), (&mut self.y, &mut compensation.y, value.y), (&mut self.z, &mut compensation.z, value.z)] {
+            let y = v - *c;
+            let t = *sum + y;
+            *c = (t - *sum) - y;
+            *sum = t;
+        }
+    }
+
+    // As sum(), with Kahan summation
+    pub fn sum_compensated(iter: impl Iterator<Item = Pos>) -> Pos {
+        let mut total = Pos::default();
+        let mut compensation = Pos::default();
+        for pos in iter {
+            total.add_compensated(pos, &mut compensation);
+        }
+        total
+    }
 }
 
 impl ops::AddAssign<Pos> for Pos {
@@ -83,59 +124,219 @@ impl iter::Sum for Pos {
     }
 }
 
-#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct Body
 {
     pub id: uuid::Uuid,
     pub velocity: Pos,
     pub position: Pos,
     pub mass: f64,
+    // Metadata for people and clients to go by. The physics ignores all of it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Any CSS color, such as "#3a7bd5" or "orange"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Motion::is_free")]
+    pub motion: Motion,
+    // Feels the massive bodies' gravity but exerts none, whatever its mass says
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub test_particle: bool,
+    // Rounding error carried over from the last tick when the physics is compensated
+    #[serde(default, skip_serializing_if = "Compensation::is_zero")]
+    pub compensation: Compensation,
+}
+
+// What the last compensated additions to a body's position and velocity couldn't hold, to be added back by the next ones
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Compensation
+{
+    pub position: Pos,
+    pub velocity: Pos,
+}
+
+impl Compensation {
+    pub fn is_zero(&self) -> bool {
+        self.position.is_zero() && self.velocity.is_zero()
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl Body {
 
     pub fn new() -> Body {
-        let mut b = Body::default();
-        b.id = Uuid::new_v4();
-        b
+        Body {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        }
+    }
+
+    // Sets whichever of name, color and radius are given and merges properties in, removing any set to null
+    pub fn set_metadata(&mut self, name: &Option<String>, color: &Option<String>, radius: Option<f64>, properties: &BTreeMap<String, serde_json::Value>) {
+        if name.is_some() {
+            self.name.clone_from(name);
+        }
+        if color.is_some() {
+            self.color.clone_from(color);
+        }
+        self.radius = radius.or(self.radius);
+        for (key, value) in properties {
+            if value.is_null() {
+                self.properties.remove(key);
+            } else {
+                self.properties.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    // Pinned bodies stop where they are and driven ones jump onto their trajectory at time.
+    // An invalid trajectory leaves the body as it was.
+    pub fn set_motion(&mut self, motion: &Motion, time: f64) -> Result<(), String> {
+        motion.validate()?;
+        match motion {
+            Motion::Free => {},
+            Motion::Pinned => self.velocity = Pos::default(),
+            Motion::Driven { trajectory } => (self.position, self.velocity) = trajectory.state(time),
+        }
+        self.motion = motion.clone();
+        Ok(())
+    }
+
+    // The mass the body pulls with, which is none for a test particle
+    pub fn gravitating_mass(&self) -> f64 {
+        if self.test_particle { 0.0 } else { self.mass }
     }
 
-    pub fn get_force(&self, other: &Body) -> f64 {
-        let d = self.position.dist_sq(other.position);
-        if d == 0.0 {
+    // Acceleration towards other per unit of separation, with the squared softening length added to the
+    // squared separation, so get_softened_pull can scale the separation vector by it
+    pub fn get_softened_force(&self, other: &Body, g: f64, softening: f64) -> f64 {
+        let r = self.position.dist_sq(other.position);
+        if r == 0.0 {
             return  0.0;
         }
-        let x = (NEWTONIAN_CONSTANT_OF_GRAVITATION * self.mass + other.mass) / d;
-        x
+        let d = r + softening * softening;
+        g * other.gravitating_mass() / (d * d.sqrt())
     }
 
-    pub fn get_pull(&self, other: &Body) -> Pos {
-        (other.position - self.position) * self.get_force(other)
+    pub fn get_softened_pull(&self, other: &Body, g: f64, softening: f64) -> Pos {
+        (other.position - self.position) * self.get_softened_force(other, g, softening)
     }
 
-    pub fn update_velocity(&mut self, other_bodies: Vec<&Body>) {
-        //let other_bodies: Vec<&Body> = bodies.iter().filter(|&b| b.id != self.id).collect();
-        let grav_sum: Pos = other_bodies.iter().map(|b| self.get_pull(b)).sum();
-        self.velocity += grav_sum;
+    pub fn tick(&mut self, dt: f64) {
+        self.position += self.velocity * dt;
     }
 
-    pub fn tick(&mut self) {
-        self.position += self.velocity;
+    // Position and velocity as they'd be compared for equality, which Pos can't do on its own
+    pub fn state_bits(&self) -> [u64; 6] {
+        [self.position.x, self.position.y, self.position.z, self.velocity.x, self.velocity.y, self.velocity.z].map(f64::to_bits)
     }
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+// Bump whenever a change alters how an existing node's universe evolves, such as a fix to the force law.
+// Multiverses opened under a different version drop their cached universes and recompute them.
+pub const PHYSICS_VERSION: u32 = 1;
+
+// Settings that control how a universe evolves. Stored with each universe so that
+// changing the server defaults doesn't alter universes that were already simulated.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Physics
+{
+    pub gravitational_constant: f64,
+    // Simulated time that passes per tick
+    pub timestep: f64,
+    // Derive ids and sum forces in a fixed order so recomputing always gives the same universe, see determinism.rs
+    pub deterministic: bool,
+    // Integrate each tick adaptively, keeping the estimated error per substep within this.
+    // It's both relative and absolute, so quantities near zero are held to it too. None takes fixed steps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<f64>,
+    // How fixed steps are taken, see integrator.rs
+    #[serde(skip_serializing_if = "Integrator::is_default")]
+    pub integrator: Integrator,
+    // Sum forces, and add fixed steps to positions and velocities, with Kahan summation. Slower, but rounding
+    // error stops piling up over long runs.
+    #[serde(skip_serializing_if = "is_false")]
+    pub compensated: bool,
+}
+
+impl Default for Physics {
+    fn default() -> Self {
+        Physics {
+            gravitational_constant: NEWTONIAN_CONSTANT_OF_GRAVITATION,
+            timestep: 1.0,
+            deterministic: false,
+            tolerance: None,
+            integrator: Integrator::default(),
+            compensated: false,
+        }
+    }
+}
+
+impl Physics {
+    // Adaptive steps take their own Dormand-Prince steps with plain sums, so the integrator and compensated
+    // settings for fixed steps would be silently ignored alongside a tolerance
+    pub fn validate_stepping(&self) -> Result<(), String> {
+        if self.tolerance.is_some() && (!self.integrator.is_default() || self.compensated) {
+            return Err(String::from("A tolerance can't be combined with another integrator or compensated sums, which only apply to fixed steps"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Universe
 {
     pub id: uuid::Uuid,
     pub bodies: Vec<Body>,
+    #[serde(default)]
+    pub physics: Physics,
+    // Simulated time since the root, which drives the trajectories of driven bodies
+    #[serde(default)]
+    pub time: f64,
+    // Acting alongside Newtonian gravity, or in place of it, see forces.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forces: Vec<Force>,
+    // Integration steps taken since the node this universe belongs to started, one per tick at a fixed timestep
+    #[serde(default)]
+    pub substeps: u64,
+    // The adaptive integrator's next step size, carried from one tick to the next
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step: Option<f64>,
+    // Ticks since the node this universe belongs to started
+    #[serde(default)]
+    pub ticks: u64,
+    // What to look out for during ticks, and what was seen in this node's ticks, see events.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub detectors: Vec<Detector>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<Event>,
 }
 
 impl Universe {
     pub fn new() -> Universe {
+        Universe::with_physics(Physics::default())
+    }
+
+    pub fn with_physics(physics: Physics) -> Universe {
         Universe{
             id: Uuid::new_v4(),
-            bodies: vec![]
+            bodies: vec![],
+            physics,
+            time: 0.0,
+            forces: vec![],
+            substeps: 0,
+            step: None,
+            ticks: 0,
+            detectors: vec![],
+            events: vec![],
         }
     }
 
@@ -143,6 +344,19 @@ impl Universe {
         self.bodies.iter().find(|b| b.id == id)
     }
 
+    // Names needn't be unique, so this is every body going by name
+    pub fn find_by_name(&self, name: &str) -> Vec<usize> {
+        self.bodies.iter().enumerate()
+            .filter(|(_, b)| b.name.as_deref() == Some(name))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Per-body differences from self to other
+    pub fn diff(&self, other: &Universe) -> UniverseDiff {
+        UniverseDiff::new(self, other)
+    }
+
     pub fn get_body_mut(&mut self, id: uuid::Uuid) -> Option<&mut Body> {
         self.bodies.iter_mut().find(|b| b.id == id)
     }
@@ -152,26 +366,47 @@ impl Universe {
     }
 
     pub fn remove_body(&mut self, id: &uuid::Uuid) {
-        self.bodies.retain(|&b| b.id != *id);
+        self.bodies.retain(|b| b.id != *id);
     }
 
-    pub fn remove_bodies(&mut self, ids: &Vec<Handle>) {
-        //self.bodies.retain(|b| !ids.contains(&b.id));
+    pub fn remove_bodies(&mut self, ids: &[Uuid]) {
+        self.bodies.retain(|b| !ids.contains(&b.id));
     }
 
-    pub fn tick(&mut self) {
-        let mut grav_sums: Vec<Pos> = vec![];
-        for body in &self.bodies {
-            let grav_sum: Pos = self.bodies.iter().map(|b| body.get_pull(b)).sum();
-            grav_sums.push(grav_sum);
+    // Acceleration of every body from gravity and the universe's forces, as things stand
+    pub fn accelerations(&self) -> Vec<Pos> {
+        let mut accelerations = vec![Pos::default(); self.bodies.len()];
+        if !self.forces.iter().any(|f| f.model().replaces_gravity()) {
+            forces::newtonian(self, 0.0, &mut accelerations);
         }
-
-        let deltas = self.bodies.iter_mut().zip(grav_sums);
-        for delta in deltas {
-            delta.0.velocity += delta.1
+        for force in &self.forces {
+            force.model().accelerate(self, &mut accelerations);
         }
+        accelerations
+    }
+
+    // Puts pinned and driven bodies where their motion says they are at the universe's time
+    pub fn follow_motion(&mut self) {
         for body in &mut self.bodies {
-            body.tick();
+            match &body.motion {
+                Motion::Free => {},
+                Motion::Pinned => body.velocity = Pos::default(),
+                Motion::Driven { trajectory } => (body.position, body.velocity) = trajectory.state(self.time),
+            }
+        }
+    }
+
+    // Advances by one timestep, in a single step or, with a tolerance set, in as many substeps as that needs
+    pub fn tick(&mut self) {
+        let before = (!self.detectors.is_empty()).then(|| events::state(self));
+        match self.physics.tolerance {
+            Some(tolerance) => integrator::adaptive_tick(self, tolerance),
+            None => integrator::fixed_tick(self),
+        }
+        self.ticks += 1;
+        if let Some(before) = before {
+            let found = events::detect(self, &before);
+            self.events.extend(found);
         }
     }
 
@@ -180,4 +415,44 @@ impl Universe {
             self.tick();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensated_sums_keep_increments_plain_addition_loses() {
+        // Each increment is under half an ulp of 1, so adding it on its own rounds it away
+        let small = Pos { x: 1e-16, y: -5e-17, z: 0.0 };
+        let values = || std::iter::once(Pos { x: 1.0, y: 1.0, z: 1.0 }).chain(std::iter::repeat_n(small, 1000));
+        let plain: Pos = values().sum();
+        assert_eq!((plain.x, plain.y), (1.0, 1.0));
+        let compensated = Pos::sum_compensated(values());
+        assert!((compensated.x - (1.0 + 1e-13)).abs() < 1e-15);
+        assert!((compensated.y - (1.0 - 5e-14)).abs() < 1e-15);
+        assert_eq!(compensated.z, 1.0);
+    }
+
+    #[test]
+    fn test_particles_feel_gravity_without_moving_massive_bodies() {
+        for integrator in [Integrator::SymplecticEuler, Integrator::Yoshida4] {
+            let mut universe = Universe::with_physics(Physics { gravitational_constant: 1.0, timestep: 0.01, integrator, ..Default::default() });
+            universe.add_body(Body { mass: 1.0, ..Body::new() });
+            universe.add_body(Body { mass: 0.1, position: Pos { x: 1.0, y: 0.0, z: 0.0 }, velocity: Pos { x: 0.0, y: 1.0, z: 0.0 }, ..Body::new() });
+            let mut with_particles = universe.clone();
+            for i in 1..=5 {
+                let position = Pos { x: 0.0, y: 0.3 * i as f64, z: 0.0 };
+                with_particles.add_body(Body { mass: 1e3, position, test_particle: true, ..Body::new() });
+            }
+
+            universe.tick_for(200);
+            with_particles.tick_for(200);
+            for (alone, beside_particles) in universe.bodies.iter().zip(&with_particles.bodies) {
+                assert_eq!(alone.state_bits(), beside_particles.state_bits());
+            }
+            // Heavy as they are, the particles were pulled in rather than pulling
+            assert!(with_particles.bodies[2..].iter().all(|p| p.velocity.length() > 0.0));
+        }
+    }
+}
@@ -1,6 +1,5 @@
-use crate::{handle::Handle, simulation};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use uuid::Uuid;
+use crate::handle::Handle;
+use serde::{de::DeserializeOwned, Serialize};
 
 use rusqlite::{self, Connection};
 
@@ -11,6 +10,9 @@ pub trait Store<T>
     fn get_handles(&self) -> Vec<Handle>;
     fn save(&self, val: T) -> Handle;
     fn save_handle(&self, val: &T, handle: Handle);
+    fn count(&self) -> usize;
+    // Flushes anything outstanding and releases the underlying storage
+    fn close(self: Box<Self>) -> Result<(), String>;
 }
 
 pub struct StoreSQL
@@ -33,44 +35,47 @@ impl StoreSQL {
 impl<T: Serialize + DeserializeOwned> Store<T> for StoreSQL {
     fn get(&self, handle: &Handle) -> Option<T> {
         let mut stmt = self.conn.prepare("SELECT id, json FROM data WHERE id = ?1").expect("Failed to prepare SQL statement");
-        match stmt.query_row([handle.id.to_string()], |row| {
+        stmt.query_row([handle.id.to_string()], |row| {
             let json: String = row.get(1).expect("Failed to get json");
-            let v: T = serde_json::from_str(&json.as_str()).expect("Failed to parse json");
+            let v: T = serde_json::from_str(json.as_str()).expect("Failed to parse json");
             Ok(v)
-        }) {
-            Ok(val) => Some(val),
-            Err(_) => None,
-        }
+        }).ok()
     }
 
     fn get_handles(&self) -> Vec<Handle> {
-        let mut ret_vec = vec![];
-        let mut stmt = self.conn.prepare("SELECT id FROM data").expect("Failed to prepare SQL statement");
-        match stmt.query_row([], |row| {
-            println!("Got a row from a database");
-            let v: String = row.get(0).unwrap();
+        let mut stmt = self.conn.prepare("SELECT id FROM data ORDER BY rowid").expect("Failed to prepare SQL statement");
+        let rows = stmt.query_map([], |row| {
+            let v: String = row.get(0)?;
             Ok(v)
-        }) {
-            Ok(id) => ret_vec.push(Handle::new_from(&id)),
-            Err(_) => ()
-        };
-        ret_vec
+        }).expect("Failed to query handles");
+        rows.filter_map(|row| row.ok())
+            .map(|id| Handle::new_from(&id))
+            .collect()
     }
 
     fn save(&self, val: T) -> Handle {
-        println!("Saving handle");
         let handle = Handle::new();
         let json = serde_json::to_string(&val).expect("Failed to serialize json");
-        println!("Inserted: {}", self.conn.execute("INSERT INTO data (id, json) VALUES (?1, ?2)", (&handle.id.to_string(), json)).expect("Failed to create row"));
+        self.conn.execute("INSERT INTO data (id, json) VALUES (?1, ?2)", (&handle.id.to_string(), json)).expect("Failed to create row");
+        log::debug!("Saved {}", handle.id);
         handle
     }
 
     fn save_handle(&self, val: &T, handle: Handle) {
         let json = serde_json::to_string(&val).expect("Failed to serialize json");
-        self.conn.execute("REPLACE INTO data (id, json) VALUES (?1, ?2)", (&handle.id.to_string(), json));
+        // An upsert rather than REPLACE, which would delete and reinsert the row and lose its place in creation order
+        self.conn.execute("INSERT INTO data (id, json) VALUES (?1, ?2) ON CONFLICT(id) DO UPDATE SET json = excluded.json", (&handle.id.to_string(), json)).expect("Failed to save row");
     }
 
     fn delete_handle(&self, handle: Handle) {
-        self.conn.execute("DELETE FROM data WHERE id == ?1", [&handle.id.to_string()]);
+        self.conn.execute("DELETE FROM data WHERE id == ?1", [&handle.id.to_string()]).expect("Failed to delete row");
+    }
+
+    fn count(&self) -> usize {
+        self.conn.query_row("SELECT COUNT(*) FROM data", [], |row| row.get(0)).expect("Failed to count rows")
+    }
+
+    fn close(self: Box<Self>) -> Result<(), String> {
+        self.conn.close().map_err(|(_, e)| e.to_string())
     }
 }
\ No newline at end of file
@@ -0,0 +1,202 @@
+// Parameter sweeps: a family of branches from one node, each a copy of a template delta with some of its
+// fields set from a grid of values. Every axis is swept against every other, so two axes of ten values
+// make a hundred branches. Each branch is tagged with the coordinates it was made from.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{multiverse::BranchParams, simulation::Pos};
+
+// Keeps a typo in a grid from filling the database
+pub const MAX_VARIANTS: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SweepField {
+    Position,
+    DPosition,
+    Velocity,
+    DVelocity,
+    Mass,
+    DMas,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SweepValues {
+    // Evenly spaced from `from` to `to` inclusive
+    Range { from: f64, to: f64, steps: usize },
+    List { values: Vec<f64> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SweepAxis
+{
+    pub field: SweepField,
+    // Required for the vector fields, which are swept one component at a time
+    pub component: Option<Component>,
+    #[serde(flatten)]
+    pub values: SweepValues,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Sweep
+{
+    pub template: BranchParams,
+    pub axes: Vec<SweepAxis>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SweepCoordinate
+{
+    pub field: SweepField,
+    pub component: Option<Component>,
+    pub value: f64,
+}
+
+// Marks a node as one point of a sweep. Nodes from the same sweep share its id.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SweepTag
+{
+    pub sweep: Uuid,
+    pub coordinates: Vec<SweepCoordinate>,
+}
+
+// One point of the grid. The multiverse gives the sweep its id when it branches the variants.
+pub struct Variant {
+    pub delta: BranchParams,
+    pub coordinates: Vec<SweepCoordinate>,
+}
+
+impl SweepValues {
+    fn values(&self) -> Vec<f64> {
+        match self {
+            SweepValues::List { values } => values.clone(),
+            SweepValues::Range { from, to: _, steps: 1 } => vec![*from],
+            SweepValues::Range { from, to, steps } => (0..*steps)
+                .map(|i| from + (to - from) * i as f64 / (*steps - 1) as f64)
+                .collect(),
+        }
+    }
+}
+
+fn set_component(pos: &mut Option<Pos>, component: Component, value: f64) {
+    let pos = pos.get_or_insert_with(Pos::default);
+    match component {
+        Component::X => pos.x = value,
+        Component::Y => pos.y = value,
+        Component::Z => pos.z = value,
+    }
+}
+
+impl SweepCoordinate {
+    fn apply(&self, delta: &mut BranchParams) {
+        let component = self.component.unwrap_or(Component::X);
+        match self.field {
+            SweepField::Position => set_component(&mut delta.position, component, self.value),
+            SweepField::DPosition => set_component(&mut delta.d_position, component, self.value),
+            SweepField::Velocity => set_component(&mut delta.velocity, component, self.value),
+            SweepField::DVelocity => set_component(&mut delta.d_velocity, component, self.value),
+            SweepField::Mass => delta.mass = Some(self.value),
+            SweepField::DMas => delta.d_mas = Some(self.value),
+        }
+    }
+}
+
+impl Sweep {
+    // Every point of the grid, with the first axis varying slowest
+    pub fn variants(&self) -> Result<Vec<Variant>, String> {
+        let mut axes = vec![];
+        for axis in &self.axes {
+            let vector = !matches!(axis.field, SweepField::Mass | SweepField::DMas);
+            if vector != axis.component.is_some() {
+                return Err(format!("{:?} {} a component", axis.field, if vector { "needs" } else { "doesn't take" }));
+            }
+            if let SweepValues::Range { steps: 0, .. } = axis.values {
+                return Err(String::from("A range needs at least one step"));
+            }
+            let values = axis.values.values();
+            if values.is_empty() || values.iter().any(|v| !v.is_finite()) {
+                return Err(String::from("Every axis needs at least one finite value"));
+            }
+            axes.push((axis, values));
+        }
+        let count = axes.iter().try_fold(1usize, |n, (_, values)| n.checked_mul(values.len()));
+        if count.is_none_or(|n| n > MAX_VARIANTS) {
+            return Err(format!("A sweep can make at most {} branches", MAX_VARIANTS));
+        }
+
+        let mut points: Vec<Vec<SweepCoordinate>> = vec![vec![]];
+        for (axis, values) in axes {
+            points = points.into_iter().flat_map(|point| {
+                values.iter().map(move |value| {
+                    let mut point = point.clone();
+                    point.push(SweepCoordinate { field: axis.field, component: axis.component, value: *value });
+                    point
+                })
+            }).collect();
+        }
+        Ok(points.into_iter().map(|coordinates| {
+            let mut delta = self.template.clone();
+            for coordinate in &coordinates {
+                coordinate.apply(&mut delta);
+            }
+            Variant { delta, coordinates }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis(field: SweepField, component: Option<Component>, values: SweepValues) -> SweepAxis {
+        SweepAxis { field, component, values }
+    }
+
+    #[test]
+    fn the_grid_covers_every_combination_with_the_first_axis_slowest() {
+        let sweep = Sweep {
+            template: BranchParams { d_mas: Some(0.5), ..Default::default() },
+            axes: vec![
+                axis(SweepField::Mass, None, SweepValues::List { values: vec![1.0, 2.0] }),
+                axis(SweepField::Velocity, Some(Component::Y), SweepValues::Range { from: 0.0, to: 1.0, steps: 3 }),
+            ],
+        };
+        let variants = sweep.variants().expect("Sweep should be valid");
+        assert_eq!(variants.len(), 6);
+        let points: Vec<(f64, f64)> = variants.iter().map(|v| (v.delta.mass.unwrap(), v.delta.velocity.unwrap().y)).collect();
+        assert_eq!(points, [(1.0, 0.0), (1.0, 0.5), (1.0, 1.0), (2.0, 0.0), (2.0, 0.5), (2.0, 1.0)]);
+        for variant in &variants {
+            let values: Vec<f64> = variant.coordinates.iter().map(|c| c.value).collect();
+            assert_eq!(values, [variant.delta.mass.unwrap(), variant.delta.velocity.unwrap().y]);
+            // Fields the axes don't touch come from the template
+            assert_eq!(variant.delta.d_mas, Some(0.5));
+            assert_eq!(variant.delta.velocity.unwrap().x, 0.0);
+        }
+    }
+
+    #[test]
+    fn invalid_grids_are_rejected() {
+        let invalid = [
+            vec![axis(SweepField::Mass, Some(Component::X), SweepValues::List { values: vec![1.0] })],
+            vec![axis(SweepField::Position, None, SweepValues::List { values: vec![1.0] })],
+            vec![axis(SweepField::Mass, None, SweepValues::Range { from: 0.0, to: 1.0, steps: 0 })],
+            vec![axis(SweepField::Mass, None, SweepValues::List { values: vec![f64::NAN] })],
+            vec![axis(SweepField::Mass, None, SweepValues::Range { from: 0.0, to: 1.0, steps: 101 }); 2],
+        ];
+        for axes in invalid {
+            assert!(Sweep { template: BranchParams::default(), axes }.variants().is_err());
+        }
+        let single = Sweep { template: BranchParams::default(), axes: vec![axis(SweepField::DMas, None, SweepValues::Range { from: 3.0, to: 9.0, steps: 1 })] };
+        assert_eq!(single.variants().expect("Sweep should be valid")[0].delta.d_mas, Some(3.0));
+    }
+}
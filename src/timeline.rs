@@ -1,10 +1,11 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::multiverse::Multiverse;
 use crate::multiverse::MultiverseNode;
 use crate::simulation::Universe;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Timeline
 {
     pub universes: Vec<Universe>,
@@ -15,10 +16,7 @@ impl Timeline {
         let nodes = node.get_lineage(multiverse);
         Timeline {
             universes: nodes.iter().filter_map(|h| {
-                match multiverse.get_node(h) {
-                    Some(n) => Some(n.get_universe(&multiverse)),
-                    None => None
-                }
+                multiverse.get_node(h).map(|n| n.get_universe(multiverse))
             }).collect()
         }
     }